@@ -1,33 +1,77 @@
 use std::collections::VecDeque;
 use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use clap::{CommandFactory, FromArgMatches};
-use futures::Future;
+use futures::future::BoxFuture;
+use futures::{Future, FutureExt};
 use imbl_value::imbl::OrdMap;
 use imbl_value::Value;
 use reqwest::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
-use reqwest::{Client, Method};
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use url::Url;
 use yajrc::{Id, RpcError};
 
+use crate::client::{ClientError, TransportError};
+use crate::completion::{
+    completions_command, print_completions, try_complete, write_completions, COMPLETIONS_COMMAND,
+};
+use crate::config::ConfigLayers;
+use crate::docs::{docs_command, markdown, print_docs, write_man_pages, DOCS_COMMAND};
+use crate::handler::deprecation;
+use crate::progress::{with_progress_bar, ProgressNotification};
+use crate::shell::{shell_command, Shell, SHELL_COMMAND};
+use crate::telemetry::{instrument_call, TRACEPARENT};
 use crate::util::{internal_error, invalid_params, parse_error, without, Flat, PhantomData};
+use crate::version::VersionExchange;
 use crate::{
-    AnyHandler, CliBindings, CliBindingsAny, Empty, HandleAny, HandleAnyArgs, HandlerArgs,
-    HandlerArgsFor, HandlerFor, HandlerTypes, Name, ParentHandler, PrintCliResult,
+    AnyHandler, ApiVersion, CliBindings, CliBindingsAny, Empty, HandleAny, HandleAnyArgs,
+    HandlerArgs, HandlerArgsFor, HandlerFor, HandlerTypes, OutputFormat, ParentHandler,
+    PrintCliResult, ProgressReporter, TraceContext, API_VERSION_HEADER, VERSION_METHOD,
 };
 
 type GenericRpcMethod<'a> = yajrc::GenericRpcMethod<&'a str, Value, Value>;
 type RpcRequest<'a> = yajrc::RpcRequest<GenericRpcMethod<'a>>;
 type RpcResponse<'a> = yajrc::RpcResponse<GenericRpcMethod<'static>>;
 
+type RootHandler<Context> = AnyHandler<Context, Empty, ParentHandler<Context>>;
+
+enum ParsedCli<Context: crate::Context + Clone> {
+    Call(Context, RootHandler<Context>, clap::ArgMatches),
+    Shell(Context, RootHandler<Context>, Box<Shell<Context>>),
+    Rpc(
+        Context,
+        OutputFormat,
+        BoxFuture<'static, Result<Value, RpcError>>,
+    ),
+}
+
+type CallRemoteFn<Context> = Box<
+    dyn Fn(
+            Context,
+            String,
+            OrdMap<&'static str, Value>,
+            Value,
+        ) -> BoxFuture<'static, Result<Value, RpcError>>
+        + Send
+        + Sync,
+>;
+
 pub struct CliApp<Context: crate::Context + Clone, Config: CommandFactory + FromArgMatches> {
     _phantom: PhantomData<(Context, Config)>,
     make_ctx: Box<dyn FnOnce(Config) -> Result<Context, RpcError> + Send + Sync>,
     root_handler: ParentHandler<Context>,
     mut_cmd: Option<Box<dyn FnOnce(clap::Command) -> clap::Command + Send + Sync>>,
+    docs_command: bool,
+    shell_command: bool,
+    shell_history: Option<PathBuf>,
+    config_layers: ConfigLayers,
+    call_remote: Option<CallRemoteFn<Context>>,
+    api_version: Option<ApiVersion>,
 }
 impl<Context: crate::Context + Clone, Config: CommandFactory + FromArgMatches>
     CliApp<Context, Config>
@@ -41,6 +85,12 @@ impl<Context: crate::Context + Clone, Config: CommandFactory + FromArgMatches>
             make_ctx: Box::new(make_ctx),
             root_handler,
             mut_cmd: None,
+            docs_command: false,
+            shell_command: false,
+            shell_history: None,
+            config_layers: ConfigLayers::default(),
+            call_remote: None,
+            api_version: None,
         }
     }
     pub fn mutate_command(
@@ -54,12 +104,101 @@ impl<Context: crate::Context + Clone, Config: CommandFactory + FromArgMatches>
         };
         self
     }
+    /// Adds a hidden `docs` subcommand that writes man pages (`docs man <DIR>`) or a Markdown
+    /// reference (`docs markdown`) for the app.
+    pub fn with_docs_command(mut self) -> Self {
+        self.docs_command = true;
+        self
+    }
+    /// Reads argument defaults from a config file, if it exists. The format is JSON, YAML or TOML,
+    /// by the `.json`, `.yaml` or `.yml`, and `.toml` extensions; any other is an error.
+    ///
+    /// Top level keys set the defaults for the app's own arguments, keyed by argument id, and a
+    /// table named after a subcommand sets the defaults for that subcommand's params:
+    ///
+    /// ```toml
+    /// host = "https://example.com"
+    ///
+    /// [package.install]
+    /// version = "1.0.0"
+    /// ```
+    ///
+    /// Flags take precedence over environment variables (see [`CliApp::with_env_prefix`]), which
+    /// take precedence over config files, with later files overriding earlier ones. Environment
+    /// variables only set the app's own arguments, not subcommand params.
+    pub fn with_config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_layers.files.push(path.into());
+        self
+    }
+    /// Reads defaults for the app's own arguments from `{PREFIX}_{ARG_ID}` environment
+    /// variables, e.g. `MYAPP_HOST` for the `host` argument with prefix `MYAPP`.
+    pub fn with_env_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config_layers.env_prefix = Some(prefix.into());
+        self
+    }
+    /// Adds a `shell` subcommand that makes one context and then reads commands from an
+    /// interactive prompt until `exit` or end of input.
+    pub fn with_shell_command(mut self) -> Self {
+        self.shell_command = true;
+        self
+    }
+    /// Loads and saves the `shell` subcommand's history at `path`.
+    pub fn with_shell_history(mut self, path: impl Into<PathBuf>) -> Self {
+        self.shell_history = Some(path.into());
+        self
+    }
+    /// Adds an `rpc <METHOD> [PARAMS]` subcommand that sends any method, with params as JSON,
+    /// through the context's [`CallRemote`] implementation and prints the raw result. This
+    /// reaches methods without CLI bindings, or that the server has but this binary doesn't.
+    pub fn with_rpc_command<RemoteContext>(mut self) -> Self
+    where
+        Context: CallRemote<RemoteContext>,
+    {
+        self.call_remote = Some(Box::new(|ctx, method, metadata, params| {
+            async move { ctx.call_remote(&method, metadata, params, Empty {}).await }.boxed()
+        }));
+        self
+    }
+    /// Declares the API version this cli was built against to the servers it calls through
+    /// [`call_remote_http`] or [`call_remote_socket`], so that they answer as that version would,
+    /// and warns when a server's own version is incompatible with it (see
+    /// [`ApiVersion::is_compatible_with`]).
+    pub fn with_api_version(mut self, version: ApiVersion) -> Self {
+        self.api_version = Some(version);
+        self
+    }
+    fn builtin_enabled(&self, name: &str) -> bool {
+        let enabled = match name {
+            COMPLETIONS_COMMAND => true,
+            DOCS_COMMAND => self.docs_command,
+            SHELL_COMMAND => self.shell_command,
+            RPC_COMMAND => self.call_remote.is_some(),
+            _ => false,
+        };
+        enabled && self.root_handler.subcommands.get(name).is_none()
+    }
+    fn handler_commands(
+        root_handler: &ParentHandler<Context>,
+        cmd: clap::Command,
+    ) -> clap::Command {
+        cmd.subcommands(root_handler.subcommand_commands())
+    }
     fn command(&mut self) -> clap::Command {
-        let mut cmd = Config::command();
-        for (name, handler) in &self.root_handler.subcommands.1 {
-            if let (Name(name), Some(cli)) = (name, handler.cli()) {
-                cmd = cmd.subcommand(cli.cli_command().name(name));
-            }
+        let mut cmd = Self::handler_commands(
+            &self.root_handler,
+            Config::command().arg(OutputFormat::arg()),
+        );
+        if self.builtin_enabled(COMPLETIONS_COMMAND) {
+            cmd = cmd.subcommand(completions_command());
+        }
+        if self.builtin_enabled(DOCS_COMMAND) {
+            cmd = cmd.subcommand(docs_command());
+        }
+        if self.builtin_enabled(SHELL_COMMAND) {
+            cmd = cmd.subcommand(shell_command());
+        }
+        if self.builtin_enabled(RPC_COMMAND) {
+            cmd = cmd.subcommand(rpc_command());
         }
         if let Some(f) = self.mut_cmd.take() {
             cmd = f(cmd);
@@ -69,20 +208,176 @@ impl<Context: crate::Context + Clone, Config: CommandFactory + FromArgMatches>
     pub fn into_command(mut self) -> clap::Command {
         self.command()
     }
-    pub fn run(mut self, args: impl IntoIterator<Item = OsString>) -> Result<(), RpcError> {
+    fn metadata(
+        root_handler: &ParentHandler<Context>,
+        method: &str,
+    ) -> OrdMap<&'static str, Value> {
+        root_handler
+            .method_from_dots(method)
+            .map(|method| root_handler.metadata(method))
+            .unwrap_or_default()
+    }
+    /// Writes a man page for the app and each visible subcommand into `out_dir`, returning the
+    /// paths written.
+    pub fn write_man_pages(mut self, out_dir: impl AsRef<Path>) -> std::io::Result<Vec<PathBuf>> {
+        let cmd = self.command();
+        write_man_pages(cmd, out_dir.as_ref(), &|method| {
+            Self::metadata(&self.root_handler, method)
+        })
+    }
+    /// Renders a Markdown reference of the app and each visible subcommand.
+    pub fn markdown_reference(mut self) -> String {
         let cmd = self.command();
-        let matches = cmd.get_matches_from(args);
+        markdown(cmd, &|method| Self::metadata(&self.root_handler, method))
+    }
+    /// Renders the completion script for `shell`, as the `completions` subcommand prints it.
+    pub fn completion_script(mut self, shell: clap_complete::Shell) -> Result<String, RpcError> {
+        let mut out = Vec::new();
+        write_completions(self.command(), shell, false, &mut out)?;
+        String::from_utf8(out).map_err(internal_error)
+    }
+    fn parse(
+        mut self,
+        args: impl IntoIterator<Item = OsString>,
+    ) -> Result<Option<ParsedCli<Context>>, RpcError> {
+        let args: Vec<OsString> = args.into_iter().collect();
+        let cmd = self.command();
+        let cmd = self.config_layers.apply(cmd)?;
+        let mut make_ctx = Some(std::mem::replace(
+            &mut self.make_ctx,
+            Box::new(|_| Err(internal_error("context already made"))),
+        ));
+        if try_complete(&cmd, &args, |matches| {
+            (make_ctx.take()?)(Config::from_arg_matches(matches).ok()?).ok()
+        })? {
+            return Ok(None);
+        }
+        let matches = cmd.clone().get_matches_from(args);
+        let builtin = matches
+            .subcommand()
+            .filter(|(name, _)| self.builtin_enabled(name));
+        match builtin {
+            Some((COMPLETIONS_COMMAND, sub_matches)) => {
+                print_completions(cmd, sub_matches)?;
+                return Ok(None);
+            }
+            Some((DOCS_COMMAND, sub_matches)) => {
+                print_docs(cmd, sub_matches, &|method| {
+                    Self::metadata(&self.root_handler, method)
+                })?;
+                return Ok(None);
+            }
+            _ => (),
+        }
         let config = Config::from_arg_matches(&matches)?;
-        let ctx = (self.make_ctx)(config)?;
-        let root_handler = AnyHandler::new(self.root_handler);
-        let (method, params) = root_handler.cli_parse(&matches)?;
-        let res = root_handler.handle_sync(HandleAnyArgs {
+        let ctx = (make_ctx
+            .take()
+            .ok_or_else(|| internal_error("context already made"))?)(config)?;
+        if let (Some((RPC_COMMAND, sub_matches)), Some(call_remote)) = (builtin, &self.call_remote)
+        {
+            let method = sub_matches
+                .get_one::<String>("method")
+                .ok_or_else(|| internal_error("missing method"))?;
+            let params = match sub_matches.get_one::<String>("params") {
+                Some(params) => serde_json::from_str(params).map_err(parse_error)?,
+                None => Value::Object(Default::default()),
+            };
+            let metadata = Self::metadata(&self.root_handler, method);
+            if let Some(note) = deprecation(&metadata) {
+                eprintln!("warning: {method} is deprecated: {}", display_note(&note));
+            }
+            let format = OutputFormat::from_matches(&matches);
+            let call =
+                with_progress_bar(call_remote(ctx.clone(), method.clone(), metadata, params));
+            return Ok(Some(ParsedCli::Rpc(
+                ctx,
+                format,
+                async move {
+                    match hidden_progress(format) {
+                        Some(progress) => progress.scope(call).await,
+                        None => call.await,
+                    }
+                }
+                .boxed(),
+            )));
+        }
+        if let Some((SHELL_COMMAND, _)) = builtin {
+            let shell_cmd = self.config_layers.apply(Self::handler_commands(
+                &self.root_handler,
+                clap::Command::new(SHELL_COMMAND).arg(OutputFormat::arg()),
+            ))?;
+            let shell = Box::new(Shell::new(
+                shell_cmd,
+                format!("{}> ", cmd.get_name()),
+                ctx.clone(),
+                self.shell_history,
+            )?);
+            return Ok(Some(ParsedCli::Shell(
+                ctx,
+                AnyHandler::new(self.root_handler),
+                shell,
+            )));
+        }
+        Ok(Some(ParsedCli::Call(
+            ctx,
+            AnyHandler::new(self.root_handler),
+            matches,
+        )))
+    }
+    fn call(
+        ctx: Context,
+        root_handler: &RootHandler<Context>,
+        matches: &clap::ArgMatches,
+    ) -> Result<(), RpcError> {
+        let (method, params) = root_handler.cli_parse(matches)?;
+        warn_deprecated(root_handler.metadata(method.clone()), &method);
+        let handle = || {
+            root_handler.handle_sync(HandleAnyArgs {
+                context: ctx.clone(),
+                parent_method: VecDeque::new(),
+                method: method.clone(),
+                params: params.clone(),
+                inherited: crate::Empty {},
+            })
+        };
+        let res = match hidden_progress(OutputFormat::from_matches(matches)) {
+            Some(progress) => progress.sync_scope(handle),
+            None => handle(),
+        }?;
+        Self::display(ctx, root_handler, matches, method, params, res)
+    }
+    async fn call_async(
+        ctx: Context,
+        root_handler: &RootHandler<Context>,
+        matches: &clap::ArgMatches,
+    ) -> Result<(), RpcError> {
+        let (method, params) = root_handler.cli_parse(matches)?;
+        warn_deprecated(root_handler.metadata(method.clone()), &method);
+        let handle = root_handler.handle_async(HandleAnyArgs {
             context: ctx.clone(),
             parent_method: VecDeque::new(),
             method: method.clone(),
             params: params.clone(),
             inherited: crate::Empty {},
-        })?;
+        });
+        let res = match hidden_progress(OutputFormat::from_matches(matches)) {
+            Some(progress) => progress.scope(handle).await,
+            None => handle.await,
+        }?;
+        Self::display(ctx, root_handler, matches, method, params, res)
+    }
+    fn display(
+        ctx: Context,
+        root_handler: &RootHandler<Context>,
+        matches: &clap::ArgMatches,
+        method: VecDeque<&'static str>,
+        params: Value,
+        res: Value,
+    ) -> Result<(), RpcError> {
+        let format = OutputFormat::from_matches(matches);
+        if format != OutputFormat::Human {
+            return format.print(&res);
+        }
         root_handler.cli_display(
             HandleAnyArgs {
                 context: ctx,
@@ -92,11 +387,115 @@ impl<Context: crate::Context + Clone, Config: CommandFactory + FromArgMatches>
                 inherited: crate::Empty {},
             },
             res,
-        )?;
-        Ok(())
+        )
+    }
+    fn print_raw(format: OutputFormat, res: &Value) -> Result<(), RpcError> {
+        if format == OutputFormat::Human {
+            OutputFormat::Json.print(res)
+        } else {
+            format.print(res)
+        }
+    }
+    pub fn run(self, args: impl IntoIterator<Item = OsString>) -> Result<(), RpcError> {
+        let exchange = self.api_version.clone().map(VersionExchange::new);
+        VersionExchange::sync_scope(exchange, || self.run_inner(args))
+    }
+    fn run_inner(self, args: impl IntoIterator<Item = OsString>) -> Result<(), RpcError> {
+        match self.parse(args)? {
+            None => Ok(()),
+            Some(ParsedCli::Call(ctx, root_handler, matches)) => {
+                Self::call(ctx, &root_handler, &matches)
+            }
+            Some(ParsedCli::Rpc(ctx, format, fut)) => {
+                let res = if let Some(rt) = ctx.runtime() {
+                    rt.block_on(fut)
+                } else {
+                    tokio::runtime::Handle::current().block_on(fut)
+                }?;
+                Self::print_raw(format, &res)
+            }
+            Some(ParsedCli::Shell(ctx, root_handler, mut shell)) => {
+                while let Some(matches) = shell.next()? {
+                    let matches = match matches {
+                        Ok(matches) => matches,
+                        Err(e) => {
+                            e.print().ok();
+                            continue;
+                        }
+                    };
+                    if let Err(e) = Self::call(ctx.clone(), &root_handler, &matches) {
+                        eprintln!("{e}");
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+    /// Like [`CliApp::run`], but awaits the handler with `handle_async` instead of blocking on
+    /// [`Context::runtime`](crate::Context::runtime), so it can be called from within a tokio
+    /// runtime.
+    pub async fn run_async(self, args: impl IntoIterator<Item = OsString>) -> Result<(), RpcError> {
+        let exchange = self.api_version.clone().map(VersionExchange::new);
+        VersionExchange::scope(exchange, self.run_async_inner(args)).await
+    }
+    async fn run_async_inner(
+        self,
+        args: impl IntoIterator<Item = OsString>,
+    ) -> Result<(), RpcError> {
+        match self.parse(args)? {
+            None => Ok(()),
+            Some(ParsedCli::Call(ctx, root_handler, matches)) => {
+                Self::call_async(ctx, &root_handler, &matches).await
+            }
+            Some(ParsedCli::Rpc(_, format, fut)) => Self::print_raw(format, &fut.await?),
+            Some(ParsedCli::Shell(ctx, root_handler, mut shell)) => {
+                while let Some(matches) = shell.next_async().await? {
+                    let matches = match matches {
+                        Ok(matches) => matches,
+                        Err(e) => {
+                            e.print().ok();
+                            continue;
+                        }
+                    };
+                    if let Err(e) = Self::call_async(ctx.clone(), &root_handler, &matches).await {
+                        eprintln!("{e}");
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A reporter that hides the progress of remote calls, unless the output is for a human to
+/// read.
+fn hidden_progress(format: OutputFormat) -> Option<ProgressReporter> {
+    (format != OutputFormat::Human).then(ProgressReporter::discard)
+}
+
+fn warn_deprecated(metadata: OrdMap<&'static str, Value>, method: &VecDeque<&'static str>) {
+    let method = method.iter().copied().collect::<Vec<_>>().join(".");
+    if let Some(note) = deprecation(&metadata) {
+        eprintln!("warning: {method} is deprecated: {}", display_note(&note));
     }
 }
 
+fn display_note(note: &Value) -> String {
+    match note {
+        Value::String(s) => s.to_string(),
+        v => v.to_string(),
+    }
+}
+
+pub(crate) const RPC_COMMAND: &str = "rpc";
+
+fn rpc_command() -> clap::Command {
+    clap::Command::new(RPC_COMMAND)
+        .about("Call a method on the server directly")
+        .arg(clap::Arg::new("method").required(true))
+        .arg(clap::Arg::new("params").help("Params as JSON [default: {}]"))
+}
+
 pub trait CallRemote<RemoteContext, Extra = Empty>: crate::Context {
     fn call_remote(
         &self,
@@ -113,49 +512,112 @@ pub async fn call_remote_http(
     method: &str,
     params: Value,
 ) -> Result<Value, RpcError> {
+    Ok(call_remote_http_typed(client, url, method, params).await?)
+}
+
+/// Like [`call_remote_http`], but tells transport and protocol failures apart from the server's
+/// own errors.
+pub async fn call_remote_http_typed(
+    client: &Client,
+    url: Url,
+    method: &str,
+    params: Value,
+) -> Result<Value, ClientError> {
+    send_http(client.request(Method::POST, url), method, params).await
+}
+
+pub(crate) async fn send_http(
+    req: RequestBuilder,
+    method: &str,
+    params: Value,
+) -> Result<Value, ClientError> {
+    let id = next_request_id();
     let rpc_req = RpcRequest {
-        id: Some(Id::Number(0.into())),
+        id: Some(id.clone()),
         method: GenericRpcMethod::new(method),
         params,
     };
-    let mut req = client.request(Method::POST, url);
+    let mut req = req.header(TRACEPARENT, TraceContext::next().to_traceparent());
+    let exchange = VersionExchange::current();
+    if let Some(exchange) = &exchange {
+        req = req.header(API_VERSION_HEADER, exchange.client().to_string());
+    }
     let body;
     #[cfg(feature = "cbor")]
     {
         req = req.header(CONTENT_TYPE, "application/cbor");
         req = req.header(ACCEPT, "application/cbor, application/json");
-        body = serde_cbor::to_vec(&rpc_req)?;
+        body = serde_cbor::to_vec(&rpc_req).map_err(ClientError::protocol)?;
     }
     #[cfg(not(feature = "cbor"))]
     {
         req = req.header(CONTENT_TYPE, "application/json");
         req = req.header(ACCEPT, "application/json");
-        body = serde_json::to_vec(&rpc_req)?;
+        body = serde_json::to_vec(&rpc_req).map_err(ClientError::protocol)?;
     }
     let res = req
         .header(CONTENT_LENGTH, body.len())
         .body(body)
         .send()
         .await?;
+    if matches!(
+        res.status(),
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    ) {
+        return Err(TransportError::Status(res.status()).into());
+    }
+    if let Some(exchange) = &exchange {
+        if let Some(version) = res
+            .headers()
+            .get(API_VERSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+        {
+            exchange.record(version);
+        }
+    }
 
-    match res
+    let res = match res
         .headers()
         .get(CONTENT_TYPE)
         .and_then(|v| v.to_str().ok())
     {
-        Some("application/json") => {
-            serde_json::from_slice::<RpcResponse>(&*res.bytes().await.map_err(internal_error)?)
-                .map_err(parse_error)?
-                .result
-        }
+        Some("application/json") => serde_json::from_slice::<RpcResponse>(&*res.bytes().await?)
+            .map_err(ClientError::protocol)?,
         #[cfg(feature = "cbor")]
-        Some("application/cbor") => {
-            serde_cbor::from_slice::<RpcResponse>(&*res.bytes().await.map_err(internal_error)?)
-                .map_err(parse_error)?
-                .result
+        Some("application/cbor") => serde_cbor::from_slice::<RpcResponse>(&*res.bytes().await?)
+            .map_err(ClientError::protocol)?,
+        Some(content_type) => {
+            return Err(ClientError::protocol(format!(
+                "unexpected content type {content_type}"
+            )))
+        }
+        None => return Err(ClientError::protocol("missing content type")),
+    };
+    response_result(&id, res)
+}
+
+fn next_request_id() -> Id {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Id::Number(NEXT_ID.fetch_add(1, Ordering::Relaxed).into())
+}
+
+/// Unwraps the response to request `id`, rejecting an answer to any other request. The
+/// version is already checked when the response is deserialized.
+fn response_result(id: &Id, res: RpcResponse) -> Result<Value, ClientError> {
+    match (&res.id, &res.result) {
+        (Some(res_id), _) if res_id == id => (),
+        // a server that could not read the request cannot know its id
+        (None | Some(Id::Null), Err(_)) => (),
+        (res_id, _) => {
+            return Err(ClientError::protocol(format!(
+                "response id {} does not match request id {}",
+                serde_json::to_string(res_id).unwrap_or_default(),
+                serde_json::to_string(id).unwrap_or_default(),
+            )))
         }
-        _ => Err(internal_error("missing content type")),
     }
+    Ok(res.result?)
 }
 
 pub async fn call_remote_socket(
@@ -163,27 +625,66 @@ pub async fn call_remote_socket(
     method: &str,
     params: Value,
 ) -> Result<Value, RpcError> {
+    Ok(call_remote_socket_typed(connection, method, params).await?)
+}
+
+/// Like [`call_remote_socket`], but tells transport and protocol failures apart from the
+/// server's own errors.
+pub async fn call_remote_socket_typed(
+    connection: impl AsyncRead + AsyncWrite,
+    method: &str,
+    params: Value,
+) -> Result<Value, ClientError> {
+    let conn = connection;
+    tokio::pin!(conn);
+    let mut conn = BufReader::new(conn);
+    if let Some(exchange) = VersionExchange::current() {
+        let params = imbl_value::json!({ "version": exchange.client() });
+        match socket_request(&mut conn, VERSION_METHOD, params).await {
+            Ok(res) => {
+                if let Some(version) = res["version"].as_str().and_then(|v| v.parse().ok()) {
+                    exchange.record(version);
+                }
+            }
+            // servers that predate versioning do not know the method
+            Err(ClientError::Rpc(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    socket_request(&mut conn, method, params).await
+}
+
+async fn socket_request(
+    conn: &mut BufReader<impl AsyncRead + AsyncWrite + Unpin>,
+    method: &str,
+    params: Value,
+) -> Result<Value, ClientError> {
+    let id = next_request_id();
     let rpc_req = RpcRequest {
-        id: Some(Id::Number(0.into())),
+        id: Some(id.clone()),
         method: GenericRpcMethod::new(method),
         params,
     };
-    let conn = connection;
-    tokio::pin!(conn);
-    let mut buf = serde_json::to_vec(&rpc_req).map_err(|e| RpcError {
-        data: Some(e.to_string().into()),
-        ..yajrc::INTERNAL_ERROR
-    })?;
+    let mut buf = serde_json::to_vec(&rpc_req).map_err(ClientError::protocol)?;
     buf.push(b'\n');
-    conn.write_all(&buf).await.map_err(|e| RpcError {
-        data: Some(e.to_string().into()),
-        ..yajrc::INTERNAL_ERROR
-    })?;
-    let mut line = String::new();
-    BufReader::new(conn).read_line(&mut line).await?;
-    serde_json::from_str::<RpcResponse>(&line)
-        .map_err(parse_error)?
-        .result
+    conn.write_all(&buf).await?;
+    let progress = ProgressReporter::current();
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let value = serde_json::from_str::<Value>(&line).map_err(ClientError::protocol)?;
+        if let Some(notification) = ProgressNotification::from_value(&value) {
+            // the connection may carry the progress of other requests too
+            if let Some(progress) = progress.as_ref().filter(|_| notification.id == id) {
+                progress.report(notification.event);
+            }
+            continue;
+        }
+        let res = imbl_value::from_value::<RpcResponse>(value).map_err(ClientError::protocol)?;
+        return response_result(&id, res);
+    }
 }
 
 pub struct CallRemoteHandler<Context, RemoteContext, RemoteHandler, Extra = Empty> {
@@ -265,17 +766,19 @@ where
             .into_iter()
             .chain(handle_args.method.clone())
             .collect::<Vec<_>>();
-        match handle_args
-            .context
-            .call_remote(
-                &full_method.join("."),
+        let full_method = full_method.join(".");
+        let call = instrument_call(
+            &full_method,
+            handle_args.context.call_remote(
+                &full_method,
                 self.handler.metadata(handle_args.method),
                 without(handle_args.raw_params.clone(), &handle_args.params.1)
                     .map_err(invalid_params)?,
                 handle_args.params.1,
-            )
-            .await
-        {
+            ),
+        );
+        let res = with_progress_bar(call).await;
+        match res {
             Ok(a) => imbl_value::from_value(a)
                 .map_err(internal_error)
                 .map_err(Self::Err::from),
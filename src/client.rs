@@ -0,0 +1,272 @@
+use std::borrow::Cow;
+use std::fmt::Display;
+
+use futures::future::BoxFuture;
+use futures::{Future, FutureExt};
+use imbl_value::imbl::{OrdMap, Vector};
+use imbl_value::Value;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite};
+use url::Url;
+use yajrc::RpcError;
+
+use crate::call_remote_socket_typed;
+use crate::cli::send_http;
+
+pub const TRANSPORT_ERROR: RpcError = RpcError {
+    code: -32040,
+    message: Cow::Borrowed("Could not reach server"),
+    data: None,
+};
+
+pub const CONNECT_ERROR: RpcError = RpcError {
+    code: -32041,
+    message: Cow::Borrowed("Server not running"),
+    data: None,
+};
+
+pub const PROTOCOL_ERROR: RpcError = RpcError {
+    code: -32042,
+    message: Cow::Borrowed("Invalid response from server"),
+    data: None,
+};
+
+/// Why a call to a remote server failed.
+///
+/// This deliberately does not implement [`std::error::Error`]: yajrc converts any such error
+/// into a generic [`RpcError`], which would hide a server's own error behind `?`.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The server could not be reached, or the connection failed before it answered.
+    Transport(TransportError),
+    /// The server answered with something that is not a valid response.
+    Protocol(String),
+    /// The server handled the call and returned an error.
+    Rpc(RpcError),
+}
+impl ClientError {
+    pub(crate) fn protocol(e: impl Display) -> Self {
+        Self::Protocol(e.to_string())
+    }
+    /// Whether nothing was listening, e.g. because the server is not running.
+    pub fn is_connect(&self) -> bool {
+        match self {
+            Self::Transport(TransportError::Http(e)) => e.is_connect(),
+            Self::Transport(TransportError::Io(e)) => matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::NotFound
+            ),
+            _ => false,
+        }
+    }
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Self::Transport(TransportError::Http(e)) => e.is_timeout(),
+            Self::Transport(TransportError::Io(e)) => e.kind() == std::io::ErrorKind::TimedOut,
+            _ => false,
+        }
+    }
+}
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "could not reach server: {e}"),
+            Self::Protocol(e) => write!(f, "invalid response from server: {e}"),
+            Self::Rpc(e) => write!(f, "{e}"),
+        }
+    }
+}
+impl From<ClientError> for RpcError {
+    fn from(e: ClientError) -> Self {
+        let connect = e.is_connect();
+        let (base, data) = match e {
+            ClientError::Rpc(e) => return e,
+            ClientError::Transport(e) if connect => (CONNECT_ERROR, e.to_string()),
+            ClientError::Transport(e) => (TRANSPORT_ERROR, e.to_string()),
+            ClientError::Protocol(e) => (PROTOCOL_ERROR, e),
+        };
+        RpcError {
+            data: Some(data.into()),
+            ..base
+        }
+    }
+}
+impl From<RpcError> for ClientError {
+    fn from(e: RpcError) -> Self {
+        Self::Rpc(e)
+    }
+}
+impl From<TransportError> for ClientError {
+    fn from(e: TransportError) -> Self {
+        Self::Transport(e)
+    }
+}
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Transport(TransportError::Http(e))
+    }
+}
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Transport(TransportError::Io(e))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    #[error(transparent)]
+    Http(reqwest::Error),
+    #[error(transparent)]
+    Io(std::io::Error),
+    /// A proxy or load balancer answered in place of the server.
+    #[error("HTTP {0}")]
+    Status(StatusCode),
+}
+
+/// A call as [`ClientMiddleware`] sees it before it is sent.
+#[derive(Debug, Clone)]
+pub struct ClientRequest {
+    pub method: String,
+    pub params: Value,
+    /// The metadata of the handler being called.
+    pub metadata: OrdMap<&'static str, Value>,
+    /// Sent along with HTTP requests. Socket transports have nowhere to put them.
+    pub headers: HeaderMap,
+}
+
+pub trait ClientMiddleware: Clone + Send + Sync + 'static {
+    /// Returning an error skips sending the request.
+    #[allow(unused_variables)]
+    fn process_request(
+        &mut self,
+        request: &mut ClientRequest,
+    ) -> impl Future<Output = Result<(), ClientError>> + Send {
+        async { Ok(()) }
+    }
+    #[allow(unused_variables)]
+    fn process_response(
+        &mut self,
+        response: &mut Result<Value, ClientError>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+trait _ClientMiddleware: Send + Sync {
+    fn dyn_clone(&self) -> DynClientMiddleware;
+    fn process_request<'a>(
+        &'a mut self,
+        request: &'a mut ClientRequest,
+    ) -> BoxFuture<'a, Result<(), ClientError>>;
+    fn process_response<'a>(
+        &'a mut self,
+        response: &'a mut Result<Value, ClientError>,
+    ) -> BoxFuture<'a, ()>;
+}
+impl<T: ClientMiddleware> _ClientMiddleware for T {
+    fn dyn_clone(&self) -> DynClientMiddleware {
+        DynClientMiddleware(Box::new(self.clone()))
+    }
+    fn process_request<'a>(
+        &'a mut self,
+        request: &'a mut ClientRequest,
+    ) -> BoxFuture<'a, Result<(), ClientError>> {
+        <Self as ClientMiddleware>::process_request(self, request).boxed()
+    }
+    fn process_response<'a>(
+        &'a mut self,
+        response: &'a mut Result<Value, ClientError>,
+    ) -> BoxFuture<'a, ()> {
+        <Self as ClientMiddleware>::process_response(self, response).boxed()
+    }
+}
+
+pub struct DynClientMiddleware(Box<dyn _ClientMiddleware>);
+impl DynClientMiddleware {
+    pub fn new<M: ClientMiddleware>(middleware: M) -> Self {
+        Self(Box::new(middleware))
+    }
+}
+impl Clone for DynClientMiddleware {
+    fn clone(&self) -> Self {
+        self.0.dyn_clone()
+    }
+}
+
+/// Runs [`ClientMiddleware`] around calls made through any transport.
+///
+/// Like server middleware, the last one added sees the request first and the response last.
+/// Each call works on its own clone of the middleware.
+#[derive(Clone, Default)]
+pub struct ClientMiddlewareChain(Vector<DynClientMiddleware>);
+impl ClientMiddlewareChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn middleware<M: ClientMiddleware>(mut self, middleware: M) -> Self {
+        self.0.push_back(DynClientMiddleware::new(middleware));
+        self
+    }
+    pub(crate) async fn run<Fut>(
+        &self,
+        mut request: ClientRequest,
+        send: impl FnOnce(ClientRequest) -> Fut,
+    ) -> Result<Value, ClientError>
+    where
+        Fut: Future<Output = Result<Value, ClientError>>,
+    {
+        let mut mid = self.0.clone();
+        let mut res = async {
+            for middleware in mid.iter_mut().rev() {
+                middleware.0.process_request(&mut request).await?;
+            }
+            send(request).await
+        }
+        .await;
+        for middleware in mid.iter_mut() {
+            middleware.0.process_response(&mut res).await;
+        }
+        res
+    }
+    pub async fn call_http(
+        &self,
+        client: &Client,
+        url: Url,
+        method: &str,
+        metadata: OrdMap<&'static str, Value>,
+        params: Value,
+    ) -> Result<Value, ClientError> {
+        self.run(
+            ClientRequest {
+                method: method.to_owned(),
+                params,
+                metadata,
+                headers: HeaderMap::new(),
+            },
+            |req| async move {
+                let http = client.request(Method::POST, url).headers(req.headers);
+                send_http(http, &req.method, req.params).await
+            },
+        )
+        .await
+    }
+    pub async fn call_socket(
+        &self,
+        connection: impl AsyncRead + AsyncWrite,
+        method: &str,
+        metadata: OrdMap<&'static str, Value>,
+        params: Value,
+    ) -> Result<Value, ClientError> {
+        self.run(
+            ClientRequest {
+                method: method.to_owned(),
+                params,
+                metadata,
+                headers: HeaderMap::new(),
+            },
+            |req| async move { call_remote_socket_typed(connection, &req.method, req.params).await },
+        )
+        .await
+    }
+}
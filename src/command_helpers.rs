@@ -1,10 +1,20 @@
+use std::ffi::{OsStr, OsString};
 use std::fmt::Display;
-use std::io::Stdin;
+use std::io::{Read, Stdin};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use clap::ArgMatches;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, Command};
+use imbl_value::Value;
 pub use {clap, serde};
 
+use crate::util::combine;
+
+/// The id of the `--params-file` argument added to every leaf command.
+pub const PARAMS_FILE_ARG: &str = "params_file";
+
 pub fn default_arg_parser<T>(arg: &str, _: &ArgMatches) -> Result<T, clap::Error>
 where
     T: FromStr,
@@ -31,3 +41,198 @@ where
     .parse()
     .map_err(|e| clap::Error::raw(clap::error::ErrorKind::ValueValidation, e))
 }
+
+pub fn params_file_arg() -> clap::Arg {
+    clap::Arg::new(PARAMS_FILE_ARG)
+        .long("params-file")
+        .value_name("PATH|-")
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("Read params from a JSON or YAML object in a file, or stdin for -")
+}
+
+/// Reads a params object from `path`, or from stdin if `path` is `-`.
+pub fn read_params_file(path: &Path) -> Result<Value, clap::Error> {
+    let mut contents = String::new();
+    if path == Path::new("-") {
+        std::io::stdin().read_to_string(&mut contents)
+    } else {
+        std::fs::File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+    }
+    .map_err(|e| clap::Error::raw(ErrorKind::Io, format!("{}: {e}\n", path.display())))?;
+    // YAML is a superset of JSON
+    let params: Value = serde_yaml::from_str(&contents).map_err(|e| {
+        clap::Error::raw(
+            ErrorKind::ValueValidation,
+            format!("{}: {e}\n", path.display()),
+        )
+    })?;
+    if !matches!(params, Value::Object(_)) {
+        return Err(clap::Error::raw(
+            ErrorKind::ValueValidation,
+            format!("{}: params must be an object\n", path.display()),
+        ));
+    }
+    Ok(params)
+}
+
+/// The object passed with `--params-file`, if any.
+pub fn params_file(matches: &ArgMatches) -> Result<Option<Value>, clap::Error> {
+    matches
+        .try_get_one::<PathBuf>(PARAMS_FILE_ARG)
+        .ok()
+        .flatten()
+        .map(|path| read_params_file(path))
+        .transpose()
+}
+
+/// Makes the required args of a leaf command optional when `--params-file` is given, since the
+/// file may set them instead. [`fill_required_args`] checks for them once the file is read.
+pub fn params_file_optional(cmd: Command) -> Command {
+    cmd.mut_args(|arg| {
+        if arg.is_required_set() {
+            arg.required(false).required_unless_present(PARAMS_FILE_ARG)
+        } else {
+            arg
+        }
+    })
+    .arg(params_file_arg())
+}
+
+/// Normalizes an arg id or params key, so that `dry_run`, `dry-run` and `dryRun` compare equal.
+fn arg_key(s: &str) -> String {
+    s.chars()
+        .filter(|c| *c != '-' && *c != '_')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The command line occurrences that give `arg` the value of `value` from a params file.
+fn file_occurrences(arg: &Arg, value: &Value) -> Vec<Vec<OsString>> {
+    let raw = |value: &Value| match value {
+        Value::String(s) => OsString::from(&**s),
+        value => OsString::from(value.to_string()),
+    };
+    match value {
+        Value::Null => Vec::new(),
+        _ if !arg.get_action().takes_values() => match value {
+            Value::Bool(true) => vec![Vec::new()],
+            Value::Number(n) => vec![Vec::new(); n.as_u64().unwrap_or(0) as usize],
+            _ => Vec::new(),
+        },
+        Value::Array(values) => values.iter().map(|v| vec![raw(v)]).collect(),
+        value => vec![vec![raw(value)]],
+    }
+}
+
+/// Parses `cmd` again with the required args missing from `matches` taken from the params
+/// `file`, so that they can be parsed into the handler's params like any other. Returns `None`
+/// if no required arg is missing, and an error if the file does not set one either.
+pub fn fill_required_args(
+    cmd: Command,
+    matches: &ArgMatches,
+    file: &Value,
+) -> Result<Option<ArgMatches>, clap::Error> {
+    let missing =
+        |arg: &Arg| arg.is_required_set() && matches.value_source(arg.get_id().as_str()).is_none();
+    if !cmd.get_arguments().any(missing) {
+        return Ok(None);
+    }
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+    for arg in cmd.get_arguments() {
+        let id = arg.get_id().as_str();
+        // defaults and env variables are filled in again by clap
+        let occurrences = if missing(arg) {
+            let value = file.as_object().and_then(|file| {
+                file.iter()
+                    .find(|(key, _)| arg_key(key) == arg_key(id))
+                    .map(|(_, value)| value)
+            });
+            value.map_or_else(Vec::new, |value| file_occurrences(arg, value))
+        } else if matches.value_source(id) == Some(ValueSource::CommandLine) {
+            matches
+                .get_raw_occurrences(id)
+                .into_iter()
+                .flatten()
+                .map(|values| values.map(OsStr::to_owned).collect())
+                .collect()
+        } else {
+            continue;
+        };
+        if arg.is_positional() {
+            positionals.push((arg.get_index(), occurrences.concat()));
+            continue;
+        }
+        let flag = match (arg.get_long(), arg.get_short()) {
+            (Some(long), _) => format!("--{long}"),
+            (None, Some(short)) => format!("-{short}"),
+            (None, None) => continue,
+        };
+        for values in occurrences {
+            if !arg.get_action().takes_values() {
+                options.push(OsString::from(&flag));
+            } else if let ([value], true) = (&*values, arg.get_long().is_some()) {
+                let mut option = OsString::from(format!("{flag}="));
+                option.push(value);
+                options.push(option);
+            } else {
+                options.push(OsString::from(&flag));
+                options.extend(values);
+            }
+        }
+    }
+    positionals.sort_by_key(|(idx, _)| *idx);
+    let positionals: Vec<_> = positionals.into_iter().flat_map(|(_, v)| v).collect();
+    let name = OsString::from(cmd.get_name());
+    let separator = (!positionals.is_empty()).then(|| OsString::from("--"));
+    cmd.try_get_matches_from(
+        std::iter::once(name)
+            .chain(options)
+            .chain(separator)
+            .chain(positionals),
+    )
+    .map(Some)
+}
+
+/// Merges the params `file`, if any, into the params parsed from `matches`.
+///
+/// Params set explicitly on the command line are combined with the file, so a key in both is
+/// an error. Defaulted params are only kept if the file does not set them.
+pub fn with_params_file(
+    matches: &ArgMatches,
+    params: Value,
+    file: Option<Value>,
+) -> Result<Value, clap::Error> {
+    let Some(file) = file else {
+        return Ok(params);
+    };
+    let Value::Object(params) = params else {
+        return Err(clap::Error::raw(
+            ErrorKind::ValueValidation,
+            "params must be an object\n",
+        ));
+    };
+    let explicit: Vec<_> = matches
+        .ids()
+        .filter(|id| {
+            matches!(
+                matches.value_source(id.as_str()),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        })
+        .map(|id| arg_key(id.as_str()))
+        .collect();
+    let (flags, defaults): (imbl_value::InOMap<_, _>, imbl_value::InOMap<_, _>) = params
+        .into_iter()
+        .partition(|(key, _)| explicit.contains(&arg_key(key)));
+    let mut combined = combine(Value::Object(flags), file)
+        .map_err(|e| clap::Error::raw(ErrorKind::ArgumentConflict, e))?;
+    if let Value::Object(combined) = &mut combined {
+        for (key, value) in defaults {
+            if !combined.contains_key(&key) {
+                combined.insert(key, value);
+            }
+        }
+    }
+    Ok(combined)
+}
@@ -0,0 +1,194 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::io::Write;
+
+use clap::ArgMatches;
+use clap_complete::env::Shells;
+use clap_complete::{CompleteEnv, CompletionCandidate, Shell};
+use futures::future::BoxFuture;
+use futures::{Future, FutureExt};
+use yajrc::RpcError;
+
+use crate::util::internal_error;
+
+/// The environment variable that shells set when requesting dynamic completions.
+pub const COMPLETE_VAR: &str = "COMPLETE";
+pub(crate) const COMPLETIONS_COMMAND: &str = "completions";
+
+/// Produces candidate values for an argument during dynamic completion. Implemented for
+/// `Fn(Context) -> impl Future<Output = Result<Vec<String>, RpcError>>`.
+pub trait Completer<Context>: Clone + Send + Sync + 'static {
+    fn complete(&self, context: Context) -> BoxFuture<'static, Result<Vec<String>, RpcError>>;
+}
+impl<Context, F, Fut> Completer<Context> for F
+where
+    F: Fn(Context) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<String>, RpcError>> + Send + 'static,
+{
+    fn complete(&self, context: Context) -> BoxFuture<'static, Result<Vec<String>, RpcError>> {
+        self(context).boxed()
+    }
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Box<dyn Any>>> = RefCell::new(None);
+}
+
+pub(crate) fn completions_command() -> clap::Command {
+    clap::Command::new(COMPLETIONS_COMMAND)
+        .about("Generate shell completions")
+        .arg(
+            clap::Arg::new("shell")
+                .required(true)
+                .value_parser(clap::builder::EnumValueParser::<Shell>::new()),
+        )
+        .arg(
+            clap::Arg::new("dynamic")
+                .long("dynamic")
+                .action(clap::ArgAction::SetTrue)
+                .help("Register for completions that query the server at completion time"),
+        )
+}
+
+pub(crate) fn print_completions(cmd: clap::Command, matches: &ArgMatches) -> Result<(), RpcError> {
+    let shell = *matches
+        .get_one::<Shell>("shell")
+        .ok_or_else(|| internal_error("missing shell"))?;
+    let mut stdout = std::io::stdout().lock();
+    write_completions(cmd, shell, matches.get_flag("dynamic"), &mut stdout)?;
+    stdout.flush().map_err(internal_error)
+}
+
+/// Writes the completion script for `shell`, or with `dynamic`, the registration that asks the
+/// binary for completions at completion time.
+pub(crate) fn write_completions(
+    mut cmd: clap::Command,
+    shell: Shell,
+    dynamic: bool,
+    out: &mut impl Write,
+) -> Result<(), RpcError> {
+    let bin = cmd
+        .get_bin_name()
+        .unwrap_or_else(|| cmd.get_name())
+        .to_owned();
+    if dynamic {
+        let completer = std::env::current_exe()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| bin.clone());
+        Shells::builtins()
+            .completer(&shell.to_string())
+            .ok_or_else(|| internal_error(format!("unsupported shell: {shell}")))?
+            .write_registration(COMPLETE_VAR, cmd.get_name(), &bin, &completer, out)
+            .map_err(internal_error)?;
+    } else {
+        clap_complete::generate(shell, &mut cmd, bin, out);
+    }
+    Ok(())
+}
+
+/// Answers a dynamic completion request if [`COMPLETE_VAR`] is set, returning whether it did.
+///
+/// `make_ctx` is called with the partially typed command line, so completers registered with
+/// [`HandlerExt::with_completer`](crate::HandlerExt::with_completer) can reach the server.
+pub(crate) fn try_complete<Context: crate::Context>(
+    cmd: &clap::Command,
+    args: &[OsString],
+    make_ctx: impl FnOnce(&ArgMatches) -> Option<Context>,
+) -> Result<bool, RpcError> {
+    if std::env::var_os(COMPLETE_VAR).is_none_or(|v| v.is_empty() || v == "0") {
+        return Ok(false);
+    }
+    let words = args
+        .iter()
+        .position(|a| a == "--")
+        .map_or(&[][..], |idx| &args[idx + 1..]);
+    if let Some(ctx) = cmd
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(words)
+        .ok()
+        .and_then(|matches| make_ctx(&matches))
+    {
+        CONTEXT.with(|c| *c.borrow_mut() = Some(Box::new(ctx)));
+    }
+    let res = CompleteEnv::with_factory(|| cmd.clone())
+        .var(COMPLETE_VAR)
+        .try_complete(
+            args.iter().cloned(),
+            std::env::current_dir().ok().as_deref(),
+        );
+    CONTEXT.with(|c| c.borrow_mut().take());
+    res.map_err(internal_error)
+}
+
+/// Completes the word ending at `pos` of an interactive shell line, returning the offset the
+/// candidates replace from.
+pub(crate) fn complete_line<Context: crate::Context>(
+    cmd: &clap::Command,
+    ctx: Context,
+    line: &str,
+    pos: usize,
+) -> (usize, Vec<String>) {
+    let line = &line[..pos];
+    let Some(mut words) = shlex::split(line) else {
+        return (pos, Vec::new());
+    };
+    if line.is_empty() || line.ends_with(char::is_whitespace) {
+        words.push(String::new());
+    }
+    let start = pos.saturating_sub(words.last().map_or(0, |w| w.len()));
+    let mut args: Vec<OsString> = Vec::with_capacity(words.len() + 1);
+    if !cmd.is_no_binary_name_set() {
+        args.push(cmd.get_name().into());
+    }
+    args.extend(words.into_iter().map(OsString::from));
+    let index = args.len() - 1;
+    CONTEXT.with(|c| *c.borrow_mut() = Some(Box::new(ctx)));
+    let res = clap_complete::engine::complete(
+        &mut cmd.clone(),
+        args,
+        index,
+        std::env::current_dir().ok().as_deref(),
+    );
+    CONTEXT.with(|c| c.borrow_mut().take());
+    let candidates = res
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|c| !c.is_hide_set())
+        .map(|c| c.get_value().to_string_lossy().into_owned())
+        .collect();
+    (start, candidates)
+}
+
+/// Runs `complete` with the context of the dynamic completion request in progress, if any.
+pub(crate) fn complete_with<Context: crate::Context + Clone>(
+    completer: &impl Completer<Context>,
+) -> Vec<CompletionCandidate> {
+    let Some(ctx) = CONTEXT.with(|c| {
+        c.borrow()
+            .as_ref()
+            .and_then(|ctx| ctx.downcast_ref::<Context>())
+            .cloned()
+    }) else {
+        return Vec::new();
+    };
+    let fut = completer.complete(ctx);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .ok()?
+                .block_on(fut)
+                .ok()
+        })
+        .join()
+        .ok()
+        .flatten()
+    })
+    .unwrap_or_default()
+    .into_iter()
+    .map(CompletionCandidate::new)
+    .collect()
+}
@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+
+use clap::builder::Resettable;
+use clap::ArgAction;
+use imbl_value::Value;
+use yajrc::RpcError;
+
+use crate::util::internal_error;
+
+/// Extra sources of argument defaults for a [`CliApp`](crate::CliApp).
+///
+/// Precedence, highest first: flags, environment variables, config files (later files override
+/// earlier ones), and the defaults declared on the arguments themselves. Environment variables
+/// only reach the app's own arguments; subcommand params come from flags and config files.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConfigLayers {
+    pub files: Vec<PathBuf>,
+    pub env_prefix: Option<String>,
+}
+impl ConfigLayers {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.env_prefix.is_none()
+    }
+    /// Reads and merges the config files, skipping any that do not exist.
+    fn load(&self) -> Result<Value, RpcError> {
+        let mut merged = Value::Object(Default::default());
+        for path in &self.files {
+            if let Some(file) = load_file(path)? {
+                merge(&mut merged, file);
+            }
+        }
+        Ok(merged)
+    }
+    pub fn apply(&self, cmd: clap::Command) -> Result<clap::Command, RpcError> {
+        if self.is_empty() {
+            return Ok(cmd);
+        }
+        let file = self.load()?;
+        let env_prefix = self.env_prefix.as_deref();
+        let cmd = cmd.mut_args(|arg| {
+            let key = arg_key(&arg);
+            let env = env_prefix
+                .and_then(|prefix| std::env::var(format!("{prefix}_{}", key.to_uppercase())).ok())
+                .map(|value| Value::from(value.as_str()));
+            set_default(arg, env.as_ref().or_else(|| file.get(&*key)))
+        });
+        Ok(apply_subcommands(cmd, &file))
+    }
+}
+
+fn load_file(path: &Path) -> Result<Option<Value>, RpcError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(a) => a,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(internal_error(format!("{}: {e}", path.display()))),
+    };
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match ext {
+        "json" => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+        "toml" => toml::from_str(&contents).map_err(|e| e.to_string()),
+        _ => Err(format!(
+            "unsupported config format {ext:?}, expected json, yaml, yml or toml"
+        )),
+    }
+    .map(Some)
+    .map_err(|e| internal_error(format!("{}: {e}", path.display())))
+}
+
+fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Object(base), Value::Object(over)) => {
+            for (key, value) in over {
+                if let Some(existing) = base.get_mut(&key) {
+                    merge(existing, value);
+                } else {
+                    base.insert(key, value);
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+/// The config file key for an argument: its id with dashes replaced by underscores.
+fn arg_key(arg: &clap::Arg) -> String {
+    arg.get_id().as_str().replace('-', "_")
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some((**s).clone()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn set_default(arg: clap::Arg, value: Option<&Value>) -> clap::Arg {
+    if !arg.get_action().takes_values()
+        && !matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse)
+    {
+        return arg;
+    }
+    // clap only counts explicit values towards `required`, including `required_unless_present`
+    let optional = |arg: clap::Arg| {
+        arg.required(false)
+            .required_unless_present(Resettable::Reset)
+    };
+    match value {
+        Some(Value::Array(values)) => {
+            optional(arg).default_values(values.iter().filter_map(scalar).collect::<Vec<_>>())
+        }
+        Some(value) => match scalar(value) {
+            Some(value) => optional(arg).default_value(value),
+            None => arg,
+        },
+        None => arg,
+    }
+}
+
+/// Tables nested under a command's key hold the defaults for its subcommand of the same name.
+fn apply_subcommands(mut cmd: clap::Command, table: &Value) -> clap::Command {
+    let Value::Object(table) = table else {
+        return cmd;
+    };
+    for (name, sub_table) in table {
+        if !matches!(sub_table, Value::Object(_)) || cmd.find_subcommand(&**name).is_none() {
+            continue;
+        }
+        cmd = cmd.mut_subcommand(&**name, |sub| {
+            let sub = sub.mut_args(|arg| {
+                let key = arg_key(&arg);
+                set_default(arg, sub_table.get(&*key))
+            });
+            apply_subcommands(sub, sub_table)
+        });
+    }
+    cmd
+}
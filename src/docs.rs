@@ -0,0 +1,293 @@
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use yajrc::RpcError;
+
+use crate::util::internal_error;
+
+pub(crate) const DOCS_COMMAND: &str = "docs";
+
+/// Looks up the handler metadata for a dot-separated method name.
+pub(crate) type MetadataFn<'a> = dyn Fn(&str) -> OrdMap<&'static str, Value> + 'a;
+
+pub(crate) fn docs_command() -> clap::Command {
+    clap::Command::new(DOCS_COMMAND)
+        .about("Generate command documentation")
+        .hide(true)
+        .subcommand_required(true)
+        .subcommand(
+            clap::Command::new("man")
+                .about("Write a man page for every command")
+                .arg(
+                    clap::Arg::new("out_dir")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("markdown")
+                .about("Print a Markdown command reference")
+                .arg(
+                    clap::Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Write to a file instead of stdout"),
+                ),
+        )
+}
+
+pub(crate) fn print_docs(
+    cmd: clap::Command,
+    matches: &ArgMatches,
+    metadata: &MetadataFn,
+) -> Result<(), RpcError> {
+    match matches.subcommand() {
+        Some(("man", matches)) => {
+            let out_dir = matches
+                .get_one::<PathBuf>("out_dir")
+                .ok_or_else(|| internal_error("missing out_dir"))?;
+            std::fs::create_dir_all(out_dir).map_err(internal_error)?;
+            write_man_pages(cmd, out_dir, metadata).map_err(internal_error)?;
+        }
+        Some(("markdown", matches)) => {
+            let md = markdown(cmd, metadata);
+            if let Some(path) = matches.get_one::<PathBuf>("output") {
+                std::fs::write(path, md).map_err(internal_error)?;
+            } else {
+                std::io::stdout()
+                    .lock()
+                    .write_all(md.as_bytes())
+                    .map_err(internal_error)?;
+            }
+        }
+        _ => return Err(internal_error("unknown docs format")),
+    }
+    Ok(())
+}
+
+fn prepare(cmd: clap::Command) -> clap::Command {
+    let mut cmd = cmd.disable_help_subcommand(true);
+    cmd.build();
+    cmd
+}
+
+fn visible_subcommands(cmd: &clap::Command) -> impl Iterator<Item = &clap::Command> {
+    cmd.get_subcommands().filter(|s| !s.is_hide_set())
+}
+
+pub(crate) fn write_man_pages(
+    cmd: clap::Command,
+    out_dir: &Path,
+    metadata: &MetadataFn,
+) -> std::io::Result<Vec<PathBuf>> {
+    fn generate(
+        cmd: &clap::Command,
+        path: &mut Vec<String>,
+        out_dir: &Path,
+        metadata: &MetadataFn,
+        written: &mut Vec<PathBuf>,
+    ) -> std::io::Result<()> {
+        let meta = if path.is_empty() {
+            OrdMap::new()
+        } else {
+            metadata(&path.join("."))
+        };
+        let man = clap_mangen::Man::new(cmd.clone());
+        let file = out_dir.join(man.get_filename());
+        let mut out = std::fs::File::create(&file)?;
+        render_man(&man, cmd, &meta, &mut out)?;
+        out.flush()?;
+        written.push(file);
+        for sub in visible_subcommands(cmd) {
+            path.push(sub.get_name().to_owned());
+            generate(sub, path, out_dir, metadata, written)?;
+            path.pop();
+        }
+        Ok(())
+    }
+    let mut written = Vec::new();
+    generate(
+        &prepare(cmd),
+        &mut Vec::new(),
+        out_dir,
+        metadata,
+        &mut written,
+    )?;
+    Ok(written)
+}
+
+fn render_man(
+    man: &clap_mangen::Man,
+    cmd: &clap::Command,
+    metadata: &OrdMap<&'static str, Value>,
+    w: &mut dyn std::io::Write,
+) -> std::io::Result<()> {
+    man.render_title(w)?;
+    man.render_name_section(w)?;
+    man.render_synopsis_section(w)?;
+    man.render_description_section(w)?;
+    if cmd.get_arguments().any(|a| !a.is_hide_set()) {
+        man.render_options_section(w)?;
+    }
+    if visible_subcommands(cmd).next().is_some() {
+        man.render_subcommands_section(w)?;
+    }
+    if !metadata.is_empty() {
+        writeln!(w, ".SH METADATA")?;
+        for (key, value) in metadata {
+            writeln!(w, ".TP")?;
+            writeln!(w, "\\fB{}\\fR", roff_escape(key))?;
+            writeln!(w, "{}", roff_escape(&value_to_string(value)))?;
+        }
+    }
+    if cmd.get_after_long_help().is_some() || cmd.get_after_help().is_some() {
+        man.render_extra_section(w)?;
+    }
+    if cmd.get_version().is_some() || cmd.get_long_version().is_some() {
+        man.render_version_section(w)?;
+    }
+    if cmd.get_author().is_some() {
+        man.render_authors_section(w)?;
+    }
+    Ok(())
+}
+
+fn roff_escape(s: &str) -> String {
+    let s = s.replace('\\', "\\e");
+    if s.starts_with('.') || s.starts_with('\'') {
+        format!("\\&{s}")
+    } else {
+        s
+    }
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_string(),
+        v => serde_json::to_string(v).unwrap_or_default(),
+    }
+}
+
+pub(crate) fn markdown(cmd: clap::Command, metadata: &MetadataFn) -> String {
+    fn render(
+        out: &mut String,
+        cmd: &clap::Command,
+        path: &mut Vec<String>,
+        metadata: &MetadataFn,
+    ) {
+        let full_name = cmd
+            .get_bin_name()
+            .unwrap_or_else(|| cmd.get_name())
+            .to_owned();
+        let level = if path.is_empty() { "#" } else { "##" };
+        writeln!(out, "{level} `{full_name}`\n").ok();
+
+        if let Some(about) = cmd.get_long_about().or_else(|| cmd.get_about()) {
+            writeln!(out, "{about}\n").ok();
+        }
+
+        let usage = cmd.clone().render_usage().to_string();
+        let usage = usage.trim_start_matches("Usage:").trim();
+        writeln!(out, "```text\n{usage}\n```\n").ok();
+
+        if !path.is_empty() {
+            let meta = metadata(&path.join("."));
+            if !meta.is_empty() {
+                writeln!(out, "**Metadata:**\n").ok();
+                for (key, value) in &meta {
+                    writeln!(out, "- `{key}`: `{}`", value_to_string(value)).ok();
+                }
+                out.push('\n');
+            }
+        }
+
+        let (positionals, options): (Vec<_>, Vec<_>) = cmd
+            .get_arguments()
+            .filter(|a| !a.is_hide_set())
+            .partition(|a| a.is_positional());
+        for (title, args) in [("Arguments", positionals), ("Options", options)] {
+            if args.is_empty() {
+                continue;
+            }
+            writeln!(out, "**{title}:**\n").ok();
+            for arg in args {
+                writeln!(out, "- {}", describe_arg(arg)).ok();
+            }
+            out.push('\n');
+        }
+
+        let subcommands = visible_subcommands(cmd).collect::<Vec<_>>();
+        if !subcommands.is_empty() {
+            writeln!(out, "**Subcommands:**\n").ok();
+            for sub in &subcommands {
+                write!(out, "- `{}`", sub.get_name()).ok();
+                if let Some(about) = sub.get_about() {
+                    write!(out, ": {about}").ok();
+                }
+                out.push('\n');
+            }
+            out.push('\n');
+        }
+
+        for sub in subcommands {
+            path.push(sub.get_name().to_owned());
+            render(out, sub, path, metadata);
+            path.pop();
+        }
+    }
+    let mut out = String::new();
+    render(&mut out, &prepare(cmd), &mut Vec::new(), metadata);
+    out
+}
+
+fn describe_arg(arg: &clap::Arg) -> String {
+    let value_names = arg
+        .get_value_names()
+        .map(|names| names.iter().map(|n| format!("<{n}>")).collect::<Vec<_>>())
+        .unwrap_or_else(|| vec![format!("<{}>", arg.get_id().as_str().to_uppercase())]);
+    let takes_value = arg.get_action().takes_values();
+    let mut name = if arg.is_positional() {
+        value_names.join(" ")
+    } else {
+        let mut flags = Vec::new();
+        if let Some(short) = arg.get_short() {
+            flags.push(format!("-{short}"));
+        }
+        if let Some(long) = arg.get_long() {
+            flags.push(format!("--{long}"));
+        }
+        let mut name = flags.join(", ");
+        if takes_value {
+            name.push(' ');
+            name.push_str(&value_names.join(" "));
+        }
+        name
+    };
+    name = format!("`{name}`");
+    if let Some(help) = arg.get_long_help().or_else(|| arg.get_help()) {
+        write!(name, ": {help}").ok();
+    }
+    let possible = arg
+        .get_possible_values()
+        .into_iter()
+        .filter(|v| !v.is_hide_set())
+        .map(|v| format!("`{}`", v.get_name()))
+        .collect::<Vec<_>>();
+    if takes_value && !possible.is_empty() {
+        write!(name, " [possible values: {}]", possible.join(", ")).ok();
+    }
+    let defaults = arg
+        .get_default_values()
+        .iter()
+        .map(|v| v.to_string_lossy())
+        .collect::<Vec<_>>();
+    if !defaults.is_empty() {
+        write!(name, " [default: {}]", defaults.join(", ")).ok();
+    }
+    name
+}
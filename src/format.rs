@@ -0,0 +1,87 @@
+use std::io::{IsTerminal, Write};
+
+use clap::{ArgMatches, ValueEnum};
+use imbl_value::Value;
+use serde::{Deserialize, Serialize};
+use yajrc::RpcError;
+
+use crate::util::internal_error;
+use crate::{Table, TableStyle};
+
+/// The value of the global `--format` flag added by [`CliApp`](crate::CliApp).
+///
+/// Every format other than `human` prints the serialized result directly, bypassing the
+/// handler's display.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+    Yaml,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    Table,
+}
+impl OutputFormat {
+    pub const ARG_ID: &'static str = "format";
+    pub fn arg() -> clap::Arg {
+        clap::Arg::new(Self::ARG_ID)
+            .long("format")
+            .global(true)
+            .value_parser(clap::builder::EnumValueParser::<Self>::new())
+            .default_value("human")
+            .help("Output format")
+    }
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        matches
+            .try_get_one::<Self>(Self::ARG_ID)
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or_default()
+    }
+    /// Writes `value` to stdout. Does nothing for [`OutputFormat::Human`].
+    pub fn print(&self, value: &Value) -> Result<(), RpcError> {
+        let mut stdout = std::io::stdout().lock();
+        let pretty = stdout.is_terminal();
+        self.write_with(&mut stdout, value, pretty, &TableStyle::for_stdout())?;
+        stdout.flush().map_err(internal_error)
+    }
+    /// Writes `value` to `out` as [`OutputFormat::print`] would to a file or pipe.
+    pub fn write(&self, out: &mut impl Write, value: &Value) -> Result<(), RpcError> {
+        self.write_with(out, value, false, &TableStyle::default())
+    }
+    fn write_with(
+        &self,
+        out: &mut impl Write,
+        value: &Value,
+        pretty: bool,
+        style: &TableStyle,
+    ) -> Result<(), RpcError> {
+        match self {
+            Self::Human => (),
+            Self::Json => {
+                if pretty {
+                    serde_json::to_writer_pretty(&mut *out, value)
+                } else {
+                    serde_json::to_writer(&mut *out, value)
+                }
+                .map_err(internal_error)?;
+                writeln!(out).map_err(internal_error)?;
+            }
+            Self::Yaml => {
+                serde_yaml::to_writer(&mut *out, value).map_err(internal_error)?;
+            }
+            #[cfg(feature = "cbor")]
+            Self::Cbor => {
+                serde_cbor::to_writer(&mut *out, value).map_err(internal_error)?;
+            }
+            Self::Table => {
+                write!(out, "{}", Table::from_value(value).render_with(style))
+                    .map_err(internal_error)?;
+            }
+        }
+        Ok(())
+    }
+}
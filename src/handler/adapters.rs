@@ -1,19 +1,23 @@
 use std::any::TypeId;
 use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::io::Write;
 
 use clap::builder::{IntoResettable, StyledStr};
 use clap::{CommandFactory, FromArgMatches};
+use clap_complete::ArgValueCandidates;
 use imbl_value::imbl::OrdMap;
 use imbl_value::Value;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use yajrc::RpcError;
 
-use crate::util::{Flat, PhantomData};
+use crate::completion::complete_with;
+use crate::util::{internal_error, without, Flat, PhantomData};
 use crate::{
-    CallRemote, CallRemoteHandler, CliBindings, DynHandler, Handler, HandlerArgs, HandlerArgsFor,
-    HandlerFor, HandlerTypes, LeafHandler, OrEmpty, PrintCliResult, WithContext,
+    CallRemote, CallRemoteHandler, CliBindings, Completer, DynHandler, Handler, HandlerArgs,
+    HandlerArgsFor, HandlerFor, HandlerTypes, LeafHandler, OrEmpty, PrintCliResult, Table,
+    TableStyle, WithContext,
 };
 
 pub trait HandlerExt<Context: crate::Context>: HandlerFor<Context> + Sized {
@@ -44,6 +48,16 @@ pub trait HandlerExt<Context: crate::Context>: HandlerFor<Context> + Sized {
     fn with_about<M>(self, message: M) -> WithAbout<M, Self>
     where
         M: IntoResettable<StyledStr>;
+    fn with_sensitive(self, fields: &[&'static str]) -> WithSensitive<Self>;
+    fn deprecated(self, note: &'static str) -> Deprecated<Self>;
+    fn with_table_display(self) -> WithTableDisplay<Self>;
+    fn with_completer<C: crate::Context, F>(
+        self,
+        arg: &'static str,
+        completer: F,
+    ) -> WithCompleter<F, Self, C>
+    where
+        F: Completer<C>;
     fn no_ts(self) -> NoTS<Self>;
     fn unknown_ts(self) -> UnknownTS<Self>;
     fn custom_ts(self, params_ty: String, return_ty: String) -> CustomTS<Self>;
@@ -111,6 +125,40 @@ impl<Context: crate::Context, T: HandlerFor<Context> + Sized> HandlerExt<Context
         }
     }
 
+    fn with_sensitive(self, fields: &[&'static str]) -> WithSensitive<Self> {
+        WithSensitive {
+            handler: self,
+            fields: fields.to_vec(),
+        }
+    }
+
+    fn deprecated(self, note: &'static str) -> Deprecated<Self> {
+        Deprecated {
+            handler: self,
+            note,
+        }
+    }
+
+    fn with_table_display(self) -> WithTableDisplay<Self> {
+        WithTableDisplay { handler: self }
+    }
+
+    fn with_completer<C: crate::Context, F>(
+        self,
+        arg: &'static str,
+        completer: F,
+    ) -> WithCompleter<F, Self, C>
+    where
+        F: Completer<C>,
+    {
+        WithCompleter {
+            _phantom: PhantomData::new(),
+            arg,
+            completer,
+            handler: self,
+        }
+    }
+
     fn no_ts(self) -> NoTS<Self> {
         NoTS(self)
     }
@@ -973,6 +1021,538 @@ where
     }
 }
 
+/// Marks param fields as secret by setting the `sensitive` metadata key. Their values are
+/// replaced with [`REDACTED`](crate::util::REDACTED) in error messages and audit records.
+#[derive(Debug, Clone)]
+pub struct WithSensitive<H> {
+    handler: H,
+    fields: Vec<&'static str>,
+}
+
+impl<H: LeafHandler> LeafHandler for WithSensitive<H> {}
+
+impl<H> HandlerTypes for WithSensitive<H>
+where
+    H: HandlerTypes,
+{
+    type Params = H::Params;
+    type InheritedParams = H::InheritedParams;
+    type Ok = H::Ok;
+    type Err = H::Err;
+}
+#[cfg(feature = "ts-rs")]
+impl<H> crate::handler::HandlerTS for WithSensitive<H>
+where
+    H: crate::handler::HandlerTS,
+{
+    fn type_info(&self) -> Option<String> {
+        self.handler.type_info()
+    }
+}
+impl<Context, H> HandlerFor<Context> for WithSensitive<H>
+where
+    Context: crate::Context,
+    H: HandlerFor<Context>,
+{
+    fn handle_sync(
+        &self,
+        HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        }: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.handler.handle_sync(HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        })
+    }
+    async fn handle_async(
+        &self,
+        HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        }: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.handler
+            .handle_async(HandlerArgs {
+                context,
+                parent_method,
+                method,
+                params,
+                inherited_params,
+                raw_params,
+            })
+            .await
+    }
+    fn metadata(&self, method: VecDeque<&'static str>) -> OrdMap<&'static str, Value> {
+        let mut metadata = self.handler.metadata(method);
+        metadata.insert(
+            "sensitive",
+            Value::Array(self.fields.iter().map(|f| Value::from(*f)).collect()),
+        );
+        metadata
+    }
+    fn method_from_dots(&self, method: &str) -> Option<VecDeque<&'static str>> {
+        self.handler.method_from_dots(method)
+    }
+}
+impl<Context, H> CliBindings<Context> for WithSensitive<H>
+where
+    Context: crate::Context,
+    H: CliBindings<Context>,
+{
+    fn cli_command(&self) -> clap::Command {
+        self.handler.cli_command()
+    }
+    fn cli_parse(
+        &self,
+        arg_matches: &clap::ArgMatches,
+    ) -> Result<(VecDeque<&'static str>, Value), clap::Error> {
+        self.handler.cli_parse(arg_matches)
+    }
+    fn cli_display(
+        &self,
+        handler: HandlerArgsFor<Context, Self>,
+        result: Self::Ok,
+    ) -> Result<(), Self::Err> {
+        self.handler.cli_display(handler, result)
+    }
+}
+
+/// Marks a handler `deprecated` in its metadata, with a note such as what to use instead. It
+/// is still called, but is hidden from the cli help, docs and TypeScript types, and callers
+/// are warned.
+#[derive(Debug, Clone)]
+pub struct Deprecated<H> {
+    handler: H,
+    note: &'static str,
+}
+
+impl<H: LeafHandler> LeafHandler for Deprecated<H> {}
+
+impl<H> HandlerTypes for Deprecated<H>
+where
+    H: HandlerTypes,
+{
+    type Params = H::Params;
+    type InheritedParams = H::InheritedParams;
+    type Ok = H::Ok;
+    type Err = H::Err;
+}
+#[cfg(feature = "ts-rs")]
+impl<H> crate::handler::HandlerTS for Deprecated<H>
+where
+    H: crate::handler::HandlerTS,
+{
+    fn type_info(&self) -> Option<String> {
+        None
+    }
+}
+impl<Context, H> HandlerFor<Context> for Deprecated<H>
+where
+    Context: crate::Context,
+    H: HandlerFor<Context>,
+{
+    fn handle_sync(
+        &self,
+        HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        }: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.handler.handle_sync(HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        })
+    }
+    async fn handle_async(
+        &self,
+        HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        }: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.handler
+            .handle_async(HandlerArgs {
+                context,
+                parent_method,
+                method,
+                params,
+                inherited_params,
+                raw_params,
+            })
+            .await
+    }
+    fn metadata(&self, method: VecDeque<&'static str>) -> OrdMap<&'static str, Value> {
+        let mut metadata = self.handler.metadata(method);
+        metadata.insert("deprecated", self.note.into());
+        metadata
+    }
+    fn method_from_dots(&self, method: &str) -> Option<VecDeque<&'static str>> {
+        self.handler.method_from_dots(method)
+    }
+}
+impl<Context, H> CliBindings<Context> for Deprecated<H>
+where
+    Context: crate::Context,
+    H: CliBindings<Context>,
+{
+    fn cli_command(&self) -> clap::Command {
+        self.handler.cli_command().hide(true)
+    }
+    fn cli_parse(
+        &self,
+        arg_matches: &clap::ArgMatches,
+    ) -> Result<(VecDeque<&'static str>, Value), clap::Error> {
+        self.handler.cli_parse(arg_matches)
+    }
+    fn cli_display(
+        &self,
+        handler: HandlerArgsFor<Context, Self>,
+        result: Self::Ok,
+    ) -> Result<(), Self::Err> {
+        self.handler.cli_display(handler, result)
+    }
+}
+
+/// The `deprecated` note in the metadata of a method, if any.
+pub(crate) fn deprecation(metadata: &OrdMap<&'static str, Value>) -> Option<Value> {
+    metadata.get("deprecated").cloned()
+}
+
+/// The args [`WithTableDisplay`] adds to a command. They travel in the raw params, next to the
+/// handler's own, and are taken out before the handler is called.
+#[derive(Debug, Clone, Default, clap::Args, Deserialize, Serialize)]
+struct TableOptions {
+    /// Comma separated list of columns to display
+    #[arg(long = "columns", value_delimiter = ',')]
+    #[serde(default)]
+    table_columns: Option<Vec<String>>,
+    /// Column to sort by
+    #[arg(long = "sort")]
+    #[serde(default)]
+    table_sort: Option<String>,
+    /// Reverse the sort order
+    #[arg(long = "reverse")]
+    #[serde(default)]
+    table_reverse: bool,
+}
+impl TableOptions {
+    fn without(raw_params: Value) -> Value {
+        without(raw_params.clone(), &Self::default()).unwrap_or(raw_params)
+    }
+}
+
+/// Displays the serialized result as a [`Table`](crate::Table), adding `--columns`, `--sort` and
+/// `--reverse` to the command.
+#[derive(Debug, Clone)]
+pub struct WithTableDisplay<H> {
+    handler: H,
+}
+
+impl<H: LeafHandler> LeafHandler for WithTableDisplay<H> {}
+
+impl<H> HandlerTypes for WithTableDisplay<H>
+where
+    H: HandlerTypes,
+{
+    type Params = H::Params;
+    type InheritedParams = H::InheritedParams;
+    type Ok = H::Ok;
+    type Err = H::Err;
+}
+#[cfg(feature = "ts-rs")]
+impl<H> crate::handler::HandlerTS for WithTableDisplay<H>
+where
+    H: crate::handler::HandlerTS,
+{
+    fn type_info(&self) -> Option<String> {
+        self.handler.type_info()
+    }
+}
+impl<Context, H> HandlerFor<Context> for WithTableDisplay<H>
+where
+    Context: crate::Context,
+    H: HandlerFor<Context>,
+{
+    fn handle_sync(
+        &self,
+        HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        }: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.handler.handle_sync(HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params: TableOptions::without(raw_params),
+        })
+    }
+    async fn handle_async(
+        &self,
+        HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        }: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.handler
+            .handle_async(HandlerArgs {
+                context,
+                parent_method,
+                method,
+                params,
+                inherited_params,
+                raw_params: TableOptions::without(raw_params),
+            })
+            .await
+    }
+    fn metadata(&self, method: VecDeque<&'static str>) -> OrdMap<&'static str, Value> {
+        self.handler.metadata(method)
+    }
+    fn method_from_dots(&self, method: &str) -> Option<VecDeque<&'static str>> {
+        self.handler.method_from_dots(method)
+    }
+}
+impl<Context, H> CliBindings<Context> for WithTableDisplay<H>
+where
+    Context: crate::Context,
+    H: HandlerTypes,
+    H::Params: CommandFactory + FromArgMatches + Serialize,
+    H::Ok: Serialize,
+    H::Err: From<RpcError>,
+{
+    fn cli_command(&self) -> clap::Command {
+        <TableOptions as clap::Args>::augment_args(H::Params::command())
+    }
+    fn cli_parse(
+        &self,
+        matches: &clap::ArgMatches,
+    ) -> Result<(VecDeque<&'static str>, Value), clap::Error> {
+        <Flat<H::Params, TableOptions>>::from_arg_matches(matches).and_then(|a| {
+            Ok((
+                VecDeque::new(),
+                imbl_value::to_value(&a)
+                    .map_err(|e| clap::Error::raw(clap::error::ErrorKind::ValueValidation, e))?,
+            ))
+        })
+    }
+    fn cli_display(
+        &self,
+        handle_args: HandlerArgsFor<Context, Self>,
+        result: Self::Ok,
+    ) -> Result<(), Self::Err> {
+        let mut stdout = std::io::stdout().lock();
+        self.write_with(
+            &mut stdout,
+            handle_args.raw_params,
+            &result,
+            &TableStyle::for_stdout(),
+        )?;
+        stdout.flush().map_err(internal_error)?;
+        Ok(())
+    }
+}
+impl<H> WithTableDisplay<H>
+where
+    H: HandlerTypes,
+    H::Ok: Serialize,
+{
+    /// Writes `result` to `out` as the cli would display it to a file or pipe, with the
+    /// `--columns`, `--sort` and `--reverse` found in `raw_params`.
+    pub fn write(
+        &self,
+        out: &mut impl Write,
+        raw_params: Value,
+        result: &H::Ok,
+    ) -> Result<(), RpcError> {
+        self.write_with(out, raw_params, result, &TableStyle::default())
+    }
+    fn write_with(
+        &self,
+        out: &mut impl Write,
+        raw_params: Value,
+        result: &H::Ok,
+        style: &TableStyle,
+    ) -> Result<(), RpcError> {
+        let options: TableOptions = imbl_value::from_value(raw_params).map_err(internal_error)?;
+        let mut table = Table::from_value(&imbl_value::to_value(result).map_err(internal_error)?);
+        if let Some(sort) = &options.table_sort {
+            table.sort_by(sort, options.table_reverse);
+        }
+        if let Some(columns) = &options.table_columns {
+            table.select(columns);
+        }
+        write!(out, "{}", table.render_with(style)).map_err(internal_error)
+    }
+}
+
+/// Completes the values of `arg` by calling `completer` with the cli context when the shell
+/// requests dynamic completions (see `completions <shell> --dynamic`).
+pub struct WithCompleter<F, H, Context> {
+    _phantom: PhantomData<Context>,
+    arg: &'static str,
+    completer: F,
+    handler: H,
+}
+
+impl<F, H: LeafHandler, Context> LeafHandler for WithCompleter<F, H, Context> {}
+
+impl<Context, F: Clone, H: Clone> Clone for WithCompleter<F, H, Context> {
+    fn clone(&self) -> Self {
+        Self {
+            _phantom: PhantomData::new(),
+            arg: self.arg,
+            completer: self.completer.clone(),
+            handler: self.handler.clone(),
+        }
+    }
+}
+impl<Context, F, H: Debug> Debug for WithCompleter<F, H, Context> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WithCompleter")
+            .field("arg", &self.arg)
+            .field("handler", &self.handler)
+            .finish()
+    }
+}
+impl<F, H, Context> HandlerTypes for WithCompleter<F, H, Context>
+where
+    H: HandlerTypes,
+{
+    type Params = H::Params;
+    type InheritedParams = H::InheritedParams;
+    type Ok = H::Ok;
+    type Err = H::Err;
+}
+#[cfg(feature = "ts-rs")]
+impl<F, H, Context> crate::handler::HandlerTS for WithCompleter<F, H, Context>
+where
+    H: crate::handler::HandlerTS,
+    F: Send + Sync + Clone + 'static,
+    Context: 'static,
+{
+    fn type_info(&self) -> Option<String> {
+        self.handler.type_info()
+    }
+}
+impl<Context, F, H, C> HandlerFor<Context> for WithCompleter<F, H, C>
+where
+    Context: crate::Context,
+    C: 'static,
+    H: HandlerFor<Context>,
+    F: Send + Sync + Clone + 'static,
+{
+    fn handle_sync(
+        &self,
+        HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        }: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.handler.handle_sync(HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        })
+    }
+    async fn handle_async(
+        &self,
+        HandlerArgs {
+            context,
+            parent_method,
+            method,
+            params,
+            inherited_params,
+            raw_params,
+        }: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.handler
+            .handle_async(HandlerArgs {
+                context,
+                parent_method,
+                method,
+                params,
+                inherited_params,
+                raw_params,
+            })
+            .await
+    }
+    fn metadata(&self, method: VecDeque<&'static str>) -> OrdMap<&'static str, Value> {
+        self.handler.metadata(method)
+    }
+    fn method_from_dots(&self, method: &str) -> Option<VecDeque<&'static str>> {
+        self.handler.method_from_dots(method)
+    }
+}
+impl<Context, F, H> CliBindings<Context> for WithCompleter<F, H, Context>
+where
+    Context: crate::Context + Clone,
+    H: CliBindings<Context>,
+    F: Completer<Context>,
+{
+    fn cli_command(&self) -> clap::Command {
+        let completer = self.completer.clone();
+        self.handler.cli_command().mut_arg(self.arg, |arg| {
+            arg.add(ArgValueCandidates::new(move || complete_with(&completer)))
+        })
+    }
+    fn cli_parse(
+        &self,
+        arg_matches: &clap::ArgMatches,
+    ) -> Result<(VecDeque<&'static str>, Value), clap::Error> {
+        self.handler.cli_parse(arg_matches)
+    }
+    fn cli_display(
+        &self,
+        handler: HandlerArgsFor<Context, Self>,
+        result: Self::Ok,
+    ) -> Result<(), Self::Err> {
+        self.handler.cli_display(handler, result)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NoTS<H>(pub H);
 
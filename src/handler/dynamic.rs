@@ -0,0 +1,303 @@
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use clap::{ArgMatches, Command, CommandFactory, FromArgMatches};
+use futures::Future;
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use serde::Serialize;
+use yajrc::RpcError;
+
+use crate::handler::parent::Name;
+use crate::util::Flat;
+use crate::{
+    CliBindings, DynHandler, Empty, Handler, HandlerArgsFor, HandlerFor, HandlerRequires,
+    HandlerTypes, ParentHandler, WithContext,
+};
+#[cfg(feature = "ts-rs")]
+use crate::{CustomTS, UnknownTS};
+
+tokio::task_local! {
+    static SNAPSHOTS: Snapshots;
+}
+
+/// The subcommands each [`DynamicParentHandler`] had when a request first looked at it, so that
+/// the request's method, metadata and dispatch all agree even if the subcommands change
+/// meanwhile. Keyed by the id of the handler's shared state.
+#[derive(Clone, Default)]
+pub(crate) struct Snapshots(Arc<Mutex<HashMap<u64, Box<dyn Any + Send>>>>);
+impl Snapshots {
+    /// The snapshots of the request being handled, or new ones outside of one.
+    pub(crate) fn current_or_new() -> Self {
+        SNAPSHOTS.try_with(|s| s.clone()).unwrap_or_default()
+    }
+    pub(crate) fn sync_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        SNAPSHOTS.sync_scope(self, f)
+    }
+    pub(crate) fn scope<F: Future>(self, fut: F) -> impl Future<Output = F::Output> {
+        SNAPSHOTS.scope(self, fut)
+    }
+}
+
+/// The source of [`Shared::id`].
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Shared<Context, Params, InheritedParams> {
+    /// Unique to each handler, unlike its address, which may be reused once it is dropped.
+    id: u64,
+    current: RwLock<ParentHandler<Context, Params, InheritedParams>>,
+    /// Held while a change is made, so that concurrent changes apply one after another.
+    changing: Mutex<()>,
+}
+
+/// A [`ParentHandler`] whose subcommands can be changed while it is being served, e.g. to mount
+/// a plugin's handlers when it is installed.
+///
+/// Clones share the same subcommands, so keep one around to make changes through. Each change
+/// swaps in the new set of subcommands at once, and requests already in flight finish with the
+/// set they started with.
+pub struct DynamicParentHandler<Context, Params = Empty, InheritedParams = Empty>(
+    Arc<Shared<Context, Params, InheritedParams>>,
+);
+impl<Context, Params, InheritedParams> DynamicParentHandler<Context, Params, InheritedParams> {
+    pub fn new() -> Self {
+        Self::from(ParentHandler::new())
+    }
+    pub fn with_metadata(self, key: &'static str, value: Value) -> Self {
+        self.update(|handler| handler.with_metadata(key, value));
+        self
+    }
+    fn read(&self) -> RwLockReadGuard<'_, ParentHandler<Context, Params, InheritedParams>> {
+        self.0
+            .current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    fn write(&self) -> RwLockWriteGuard<'_, ParentHandler<Context, Params, InheritedParams>> {
+        self.0
+            .current
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    fn changing(&self) -> MutexGuard<'_, ()> {
+        self.0
+            .changing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    /// The current subcommands.
+    pub fn current(&self) -> ParentHandler<Context, Params, InheritedParams> {
+        self.read().clone()
+    }
+    /// Replaces the subcommands and metadata with those of `handler`, returning the previous
+    /// ones.
+    pub fn replace(
+        &self,
+        handler: ParentHandler<Context, Params, InheritedParams>,
+    ) -> ParentHandler<Context, Params, InheritedParams> {
+        let _changing = self.changing();
+        std::mem::replace(&mut *self.write(), handler)
+    }
+    /// Applies `f` to the current subcommands. Concurrent updates are applied one after
+    /// another, so none of them is lost. Requests are served from the previous subcommands
+    /// until `f` returns, and if it panics nothing changes.
+    pub fn update(
+        &self,
+        f: impl FnOnce(
+            ParentHandler<Context, Params, InheritedParams>,
+        ) -> ParentHandler<Context, Params, InheritedParams>,
+    ) {
+        let _changing = self.changing();
+        let handler = f(self.current());
+        *self.write() = handler;
+    }
+    /// Removes the subcommand `name`, along with any aliases of it. Returns whether it existed.
+    pub fn remove(&self, name: &str) -> bool {
+        let _changing = self.changing();
+        let mut handler = self.write();
+        let existed = handler.subcommands.1.remove(&name).is_some();
+        let aliases = std::mem::take(&mut handler.subcommands.2);
+        handler.subcommands.2 = aliases
+            .into_iter()
+            .filter(|(_, Name(target))| *target != name)
+            .collect();
+        existed
+    }
+}
+impl<Context, Params, InheritedParams> DynamicParentHandler<Context, Params, InheritedParams>
+where
+    Context: Send + 'static,
+    Params: Send + 'static,
+    InheritedParams: Send + 'static,
+{
+    /// The subcommands the current request started with, or the current ones outside of a
+    /// request.
+    fn snapshot(&self) -> ParentHandler<Context, Params, InheritedParams> {
+        let Ok(snapshots) = SNAPSHOTS.try_with(|s| s.clone()) else {
+            return self.current();
+        };
+        let mut snapshots = snapshots.0.lock().unwrap_or_else(PoisonError::into_inner);
+        snapshots
+            .entry(self.0.id)
+            .or_insert_with(|| Box::new(self.current()))
+            .downcast_ref::<ParentHandler<Context, Params, InheritedParams>>()
+            .cloned()
+            .unwrap_or_else(|| self.current())
+    }
+}
+impl<Context: crate::Context, Params, InheritedParams>
+    DynamicParentHandler<Context, Params, InheritedParams>
+{
+    /// Adds a subcommand, replacing any with the same name.
+    pub fn insert<C: crate::Context, H>(&self, name: &'static str, handler: H)
+    where
+        WithContext<C, H>: Handler<Flat<Params, InheritedParams>>,
+    {
+        if let Some(h) = DynHandler::new(handler) {
+            let _changing = self.changing();
+            self.write().subcommands.1.insert(Name(name), h);
+        }
+    }
+}
+impl<Context, Params, InheritedParams> From<ParentHandler<Context, Params, InheritedParams>>
+    for DynamicParentHandler<Context, Params, InheritedParams>
+{
+    fn from(handler: ParentHandler<Context, Params, InheritedParams>) -> Self {
+        Self(Arc::new(Shared {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            current: RwLock::new(handler),
+            changing: Mutex::new(()),
+        }))
+    }
+}
+impl<Context, Params, InheritedParams> Default
+    for DynamicParentHandler<Context, Params, InheritedParams>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<Context, Params, InheritedParams> Clone
+    for DynamicParentHandler<Context, Params, InheritedParams>
+{
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+impl<Context, Params, InheritedParams> std::fmt::Debug
+    for DynamicParentHandler<Context, Params, InheritedParams>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DynamicParentHandler")
+            .field(&self.current().subcommands)
+            .finish()
+    }
+}
+
+impl<Context, Params, InheritedParams> HandlerTypes
+    for DynamicParentHandler<Context, Params, InheritedParams>
+where
+    Params: Send + Sync,
+    InheritedParams: Send + Sync,
+{
+    type Params = Params;
+    type InheritedParams = InheritedParams;
+    type Ok = Value;
+    type Err = RpcError;
+}
+
+#[cfg(feature = "ts-rs")]
+impl<Context, Params, InheritedParams> crate::handler::HandlerTS
+    for DynamicParentHandler<Context, Params, InheritedParams>
+where
+    Params: ts_rs::TS + Send + Sync + 'static,
+    InheritedParams: Send + Sync + 'static,
+{
+    fn type_info(&self) -> Option<String> {
+        self.current().type_info_impl(&Params::inline_flattened())
+    }
+}
+#[cfg(feature = "ts-rs")]
+impl<Context, Params, InheritedParams> crate::handler::HandlerTS
+    for CustomTS<DynamicParentHandler<Context, Params, InheritedParams>>
+where
+    Params: Send + Sync + 'static,
+    InheritedParams: Send + Sync + 'static,
+{
+    fn type_info(&self) -> Option<String> {
+        self.handler.current().type_info_impl(&self.params_ty)
+    }
+}
+#[cfg(feature = "ts-rs")]
+impl<Context, Params, InheritedParams> crate::handler::HandlerTS
+    for UnknownTS<DynamicParentHandler<Context, Params, InheritedParams>>
+where
+    Params: Send + Sync + 'static,
+    InheritedParams: Send + Sync + 'static,
+{
+    fn type_info(&self) -> Option<String> {
+        self.0.current().type_info_impl("unknown")
+    }
+}
+
+impl<Context, Params, InheritedParams> HandlerFor<Context>
+    for DynamicParentHandler<Context, Params, InheritedParams>
+where
+    Self: HandlerRequires<
+        Params = Params,
+        InheritedParams = InheritedParams,
+        Ok = Value,
+        Err = RpcError,
+    >,
+    Context: crate::Context,
+    Params: Send + Sync + 'static,
+    InheritedParams: Send + Sync + 'static,
+{
+    fn handle_sync(
+        &self,
+        handle_args: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.snapshot().handle_sync(handle_args)
+    }
+    async fn handle_async(
+        &self,
+        handle_args: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        // the lock is released before awaiting, so changes never wait on a running request
+        let snapshot = self.snapshot();
+        snapshot.handle_async(handle_args).await
+    }
+    fn metadata(&self, method: VecDeque<&'static str>) -> OrdMap<&'static str, Value> {
+        self.snapshot().metadata(method)
+    }
+    fn method_from_dots(&self, method: &str) -> Option<VecDeque<&'static str>> {
+        self.snapshot().method_from_dots(method)
+    }
+}
+
+impl<Context, Params, InheritedParams> CliBindings<Context>
+    for DynamicParentHandler<Context, Params, InheritedParams>
+where
+    Context: crate::Context,
+    Params: FromArgMatches + CommandFactory + Serialize + Send + Sync + 'static,
+    InheritedParams: Send + Sync + 'static,
+{
+    fn cli_command(&self) -> Command {
+        self.current().cli_command()
+    }
+    fn cli_parse(
+        &self,
+        root_matches: &ArgMatches,
+    ) -> Result<(VecDeque<&'static str>, Value), clap::Error> {
+        self.current().cli_parse(root_matches)
+    }
+    fn cli_display(
+        &self,
+        handle_args: HandlerArgsFor<Context, Self>,
+        result: Self::Ok,
+    ) -> Result<(), Self::Err> {
+        self.current().cli_display(handle_args, result)
+    }
+}
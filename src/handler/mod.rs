@@ -13,13 +13,16 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use yajrc::RpcError;
 
-use crate::util::{internal_error, invalid_params, Flat};
+use crate::util::{internal_error, invalid_params, redact_error, Flat};
+use crate::ProgressReporter;
 
 pub mod adapters;
+pub mod dynamic;
 pub mod from_fn;
 pub mod parent;
 
 pub use adapters::*;
+pub use dynamic::*;
 pub use from_fn::*;
 pub use parent::*;
 
@@ -219,6 +222,14 @@ pub struct HandlerArgs<
     pub inherited_params: InheritedParams,
     pub raw_params: Value,
 }
+impl<Context: crate::Context, Params: Send + Sync, InheritedParams: Send + Sync>
+    HandlerArgs<Context, Params, InheritedParams>
+{
+    /// Where to report the progress of this call, if the caller is listening for it.
+    pub fn progress(&self) -> Option<ProgressReporter> {
+        ProgressReporter::current()
+    }
+}
 
 pub trait HandlerTypes {
     type Params: Send + Sync;
@@ -270,12 +281,16 @@ pub trait HandlerFor<Context: crate::Context>: HandlerRequires {
     ) -> impl Future<Output = Result<Self::Ok, Self::Err>> + Send + 'a {
         async move {
             let s = self.clone();
-            if let Some(rt) = handle_args.context.runtime() {
-                rt.spawn_blocking(move || s.handle_sync(handle_args)).await
+            let rt = handle_args.context.runtime();
+            let progress = ProgressReporter::current();
+            let f = move || match progress {
+                Some(progress) => progress.sync_scope(|| s.handle_sync(handle_args)),
+                None => s.handle_sync(handle_args),
+            };
+            if let Some(rt) = rt {
+                rt.spawn_blocking(f).await
             } else {
-                tokio::runtime::Handle::current()
-                    .spawn_blocking(move || s.handle_sync(handle_args))
-                    .await
+                tokio::runtime::Handle::current().spawn_blocking(f).await
             }
             .unwrap()
         }
@@ -365,6 +380,27 @@ where
     }
 }
 
+impl<Context, Inherited, H> AnyHandler<Context, Inherited, H>
+where
+    Context: crate::Context,
+    H: HandlerFor<Context>,
+{
+    fn redact_error(
+        &self,
+        error: RpcError,
+        method: VecDeque<&'static str>,
+        params: &Value,
+    ) -> RpcError {
+        let sensitive: Vec<String> = self
+            .handler
+            .metadata(method)
+            .get("sensitive")
+            .and_then(|s| imbl_value::from_value(s.clone()).ok())
+            .unwrap_or_default();
+        redact_error(error, params, &sensitive)
+    }
+}
+
 #[async_trait::async_trait]
 impl<Context, Inherited, H> HandleAny<Context> for AnyHandler<Context, Inherited, H>
 where
@@ -381,24 +417,30 @@ where
         &self,
         handle_args: HandleAnyArgs<Context, Self::Inherited>,
     ) -> Result<Value, RpcError> {
-        imbl_value::to_value(
-            &self
-                .handler
-                .handle_sync(handle_args.downcast::<H>().map_err(invalid_params)?)?,
-        )
-        .map_err(internal_error)
+        let (method, params) = (handle_args.method.clone(), handle_args.params.clone());
+        handle_args
+            .downcast::<H>()
+            .map_err(invalid_params)
+            .and_then(|args| self.handler.handle_sync(args).map_err(RpcError::from))
+            .and_then(|res| imbl_value::to_value(&res).map_err(internal_error))
+            .map_err(|e| self.redact_error(e, method, &params))
     }
     async fn handle_async(
         &self,
         handle_args: HandleAnyArgs<Context, Self::Inherited>,
     ) -> Result<Value, RpcError> {
-        imbl_value::to_value(
-            &self
-                .handler
-                .handle_async(handle_args.downcast::<H>().map_err(invalid_params)?)
-                .await?,
-        )
-        .map_err(internal_error)
+        let (method, params) = (handle_args.method.clone(), handle_args.params.clone());
+        async {
+            imbl_value::to_value(
+                &self
+                    .handler
+                    .handle_async(handle_args.downcast::<H>().map_err(invalid_params)?)
+                    .await?,
+            )
+            .map_err(internal_error)
+        }
+        .await
+        .map_err(|e| self.redact_error(e, method, &params))
     }
     fn metadata(&self, method: VecDeque<&'static str>) -> OrdMap<&'static str, Value> {
         self.handler.metadata(method)
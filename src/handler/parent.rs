@@ -7,6 +7,9 @@ use imbl_value::Value;
 use serde::Serialize;
 use yajrc::RpcError;
 
+use crate::command_helpers::{
+    fill_required_args, params_file, params_file_optional, with_params_file,
+};
 #[cfg(feature = "ts-rs")]
 use crate::handler::HandleAnyTS;
 use crate::util::{combine, Flat, PhantomData};
@@ -28,10 +31,12 @@ impl<'a> std::borrow::Borrow<&'a str> for Name {
 pub(crate) struct SubcommandMap<Context, Params, InheritedParams>(
     pub(crate) Option<DynHandler<Context, InheritedParams>>,
     pub(crate) OrdMap<Name, DynHandler<Context, Flat<Params, InheritedParams>>>,
+    /// Old names, and the subcommands they were renamed to.
+    pub(crate) OrdMap<Name, Name>,
 );
 impl<Context, Params, InheritedParams> Clone for SubcommandMap<Context, Params, InheritedParams> {
     fn clone(&self) -> Self {
-        Self(self.0.clone(), self.1.clone())
+        Self(self.0.clone(), self.1.clone(), self.2.clone())
     }
 }
 impl<Context, Params, InheritedParams> Debug for SubcommandMap<Context, Params, InheritedParams> {
@@ -59,16 +64,24 @@ impl<Context, Params, InheritedParams> SubcommandMap<Context, Params, InheritedP
     ) {
         self.1.insert(Name(name), handler);
     }
-    fn get<'a>(
+    /// Looks up `name`, following aliases. The name returned is the one that was called, so
+    /// that calls through an alias can be told apart.
+    pub(crate) fn get<'a>(
         &'a self,
         name: &str,
     ) -> Option<(Name, &'a DynHandler<Context, Flat<Params, InheritedParams>>)> {
         if let Some((name, handler)) = self.1.get_key_value(&name) {
             Some((*name, handler))
+        } else if let Some((alias, target)) = self.2.get_key_value(&name) {
+            Some((*alias, self.1.get(&target.0)?))
         } else {
             None
         }
     }
+    /// The subcommand that `name` was renamed to, if it is an alias.
+    fn renamed_to(&self, name: &str) -> Option<&'static str> {
+        self.2.get(&name).map(|target| target.0)
+    }
 }
 
 pub struct ParentHandler<Context, Params = Empty, InheritedParams = Empty> {
@@ -80,7 +93,7 @@ impl<Context, Params, InheritedParams> ParentHandler<Context, Params, InheritedP
     pub fn new() -> Self {
         Self {
             _phantom: PhantomData::new(),
-            subcommands: SubcommandMap(None, OrdMap::new()),
+            subcommands: SubcommandMap(None, OrdMap::new(), OrdMap::new()),
             metadata: OrdMap::new(),
         }
     }
@@ -89,7 +102,7 @@ impl<Context, Params, InheritedParams> ParentHandler<Context, Params, InheritedP
         self
     }
     #[cfg(feature = "ts-rs")]
-    fn type_info_impl(&self, params_ty: &str) -> Option<String> {
+    pub(crate) fn type_info_impl(&self, params_ty: &str) -> Option<String> {
         use std::fmt::Write;
         let mut res = "{".to_owned();
         res.push_str("_CHILDREN:{");
@@ -145,6 +158,12 @@ impl<Context: crate::Context, Params, InheritedParams>
         }
         self
     }
+    /// Keeps `old` working after a subcommand was renamed to `new`. Calls through the alias are
+    /// marked `deprecated`, and it is hidden from the cli help.
+    pub fn alias(mut self, old: &'static str, new: &'static str) -> Self {
+        self.subcommands.2.insert(Name(old), Name(new));
+        self
+    }
     pub fn root_handler<C: crate::Context, H>(mut self, handler: H) -> Self
     where
         WithContext<C, H>: Handler<InheritedParams>,
@@ -157,6 +176,34 @@ impl<Context: crate::Context, Params, InheritedParams>
     }
 }
 
+impl<Context, Params, InheritedParams> ParentHandler<Context, Params, InheritedParams>
+where
+    Context: crate::Context,
+    Params: Send + Sync + 'static,
+    InheritedParams: Send + Sync + 'static,
+{
+    /// The cli commands of each subcommand, with aliases as hidden copies of their target.
+    pub(crate) fn subcommand_commands(&self) -> impl Iterator<Item = Command> + '_ {
+        let named = self.subcommands.1.iter().map(|(name, h)| (*name, h, false));
+        let aliases = self.subcommands.2.keys().filter_map(move |alias| {
+            let (_, h) = self.subcommands.get(alias.0)?;
+            Some((*alias, h, true))
+        });
+        named
+            .chain(aliases)
+            .filter_map(|(Name(name), handler, hide)| {
+                let mut cmd = handler.cli()?.cli_command().name(name);
+                if hide {
+                    cmd = cmd.hide(true);
+                }
+                if !cmd.has_subcommands() {
+                    cmd = params_file_optional(cmd);
+                }
+                Some(cmd)
+            })
+    }
+}
+
 impl<Context, Params, InheritedParams> HandlerTypes
     for ParentHandler<Context, Params, InheritedParams>
 where
@@ -302,7 +349,12 @@ where
         let metadata = self.metadata.clone();
         if let Some(cmd) = method.pop_front() {
             if let Some((_, handler)) = self.subcommands.get(cmd) {
-                handler.metadata(method).union(metadata)
+                let mut res = handler.metadata(method).union(metadata);
+                if let Some(new) = self.subcommands.renamed_to(cmd) {
+                    res.entry("deprecated")
+                        .or_insert_with(|| format!("renamed to `{new}`").into());
+                }
+                res
             } else {
                 metadata
             }
@@ -350,20 +402,12 @@ where
     InheritedParams: Send + Sync + 'static,
 {
     fn cli_command(&self) -> Command {
-        let mut base = if let Some(cli) = &self.subcommands.0.as_ref().and_then(|h| h.cli()) {
+        let base = if let Some(cli) = &self.subcommands.0.as_ref().and_then(|h| h.cli()) {
             cli.cli_command().subcommand_required(false)
         } else {
             Params::command().subcommand_required(true)
         };
-        for (name, handler) in &self.subcommands.1 {
-            match (name, handler.cli()) {
-                (Name(name), Some(cli)) => {
-                    base = base.subcommand(cli.cli_command().name(name));
-                }
-                _ => (),
-            }
-        }
-        base
+        base.subcommands(self.subcommand_commands())
     }
     fn cli_parse(
         &self,
@@ -381,7 +425,13 @@ where
                 .get(name)
                 .and_then(|(n, h)| h.cli().map(|c| (n, c)))
             {
-                let (mut method, params) = cli.cli_parse(matches)?;
+                let file = params_file(matches)?;
+                let filled = match &file {
+                    Some(file) => fill_required_args(cli.cli_command().name(name), matches, file)?,
+                    None => None,
+                };
+                let (mut method, params) = cli.cli_parse(filled.as_ref().unwrap_or(matches))?;
+                let params = with_params_file(matches, params, file)?;
                 method.push_front(name);
 
                 Ok((
@@ -1,16 +1,37 @@
 pub use cli::*;
+pub use client::*;
+pub use completion::{Completer, COMPLETE_VAR};
 // pub use command::*;
 pub use context::*;
+pub use format::*;
 pub use handler::*;
+pub use progress::{ProgressEvent, ProgressReporter, PROGRESS_METHOD};
+pub use remote::*;
 pub use server::*;
+pub use signing::*;
+pub use table::*;
+pub use telemetry::*;
+pub use version::{available_in, ApiVersion, API_VERSION_HEADER, VERSION_METHOD};
 pub use {clap, futures, reqwest, serde, serde_json, tokio, url, yajrc};
 
 mod cli;
+mod client;
 pub mod command_helpers;
+mod completion;
+mod config;
 mod context;
+mod docs;
+mod format;
 mod handler;
+mod progress;
+mod remote;
 mod server;
+mod shell;
+mod signing;
+mod table;
+mod telemetry;
 pub mod util;
+mod version;
 
 #[cfg(feature = "ts-rs")]
 pub fn type_helpers() -> &'static str {
@@ -0,0 +1,215 @@
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+
+use futures::{Future, Stream, StreamExt};
+use imbl_value::Value;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use yajrc::Id;
+
+/// The method of the notifications that carry [`ProgressEvent`]s to the client.
+pub const PROGRESS_METHOD: &str = "progress";
+
+const BAR_WIDTH: usize = 30;
+
+tokio::task_local! {
+    static CURRENT: ProgressReporter;
+    static NOTIFICATIONS: UnboundedSender<Value>;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProgressEvent {
+    #[serde(default)]
+    pub done: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+impl ProgressEvent {
+    pub fn new(done: u64, total: Option<u64>) -> Self {
+        Self {
+            done,
+            total,
+            message: None,
+        }
+    }
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+}
+
+/// Receives the progress of the request being handled. Handlers reach it through
+/// [`HandlerArgs::progress`](crate::HandlerArgs::progress) or [`ProgressReporter::current`].
+#[derive(Clone)]
+pub struct ProgressReporter(Arc<dyn Fn(ProgressEvent) + Send + Sync>);
+impl ProgressReporter {
+    pub fn new(f: impl Fn(ProgressEvent) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|p| p.clone()).ok()
+    }
+    /// A reporter that ignores progress, so that nothing else shows it.
+    pub(crate) fn discard() -> Self {
+        Self::new(|_| ())
+    }
+    pub fn report(&self, event: ProgressEvent) {
+        (self.0)(event)
+    }
+    pub fn scope<F: Future>(self, fut: F) -> impl Future<Output = F::Output> {
+        CURRENT.scope(self, fut)
+    }
+    pub fn sync_scope<F: FnOnce() -> R, R>(self, f: F) -> R {
+        CURRENT.sync_scope(self, f)
+    }
+}
+impl std::fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ProgressReporter").finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ProgressNotification {
+    pub id: Id,
+    #[serde(flatten)]
+    pub event: ProgressEvent,
+}
+impl ProgressNotification {
+    pub fn to_value(&self) -> Value {
+        let mut notification = imbl_value::InOMap::new();
+        notification.insert("jsonrpc".into(), Value::from("2.0"));
+        notification.insert("method".into(), Value::from(PROGRESS_METHOD));
+        notification.insert(
+            "params".into(),
+            imbl_value::to_value(self).unwrap_or_default(),
+        );
+        Value::Object(notification)
+    }
+    /// Parses a line received by a client, if it is a progress notification.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        if value.get("method")?.as_str()? != PROGRESS_METHOD {
+            return None;
+        }
+        imbl_value::from_value(value.get("params")?.clone()).ok()
+    }
+}
+
+/// Lets requests handled within `fut` send progress notifications through `notifications`.
+pub(crate) fn with_notifications<F: Future>(
+    notifications: UnboundedSender<Value>,
+    fut: F,
+) -> impl Future<Output = F::Output> {
+    NOTIFICATIONS.scope(notifications, fut)
+}
+
+/// A reporter that sends the progress of request `id` as notifications, if the transport
+/// handling it supports them.
+pub(crate) fn notify(id: &Option<Id>) -> Option<ProgressReporter> {
+    let id = id.clone()?;
+    let notifications = NOTIFICATIONS.try_with(|n| n.clone()).ok()?;
+    Some(ProgressReporter::new(move |event| {
+        notifications
+            .send(
+                ProgressNotification {
+                    id: id.clone(),
+                    event,
+                }
+                .to_value(),
+            )
+            .ok();
+    }))
+}
+
+/// Interleaves notifications with `responses`, sending any that are pending before the next
+/// response so a request's progress always precedes its result.
+pub(crate) fn merge_notifications<'a, E: 'a>(
+    responses: impl Stream<Item = Result<Value, E>> + 'a,
+    mut notifications: UnboundedReceiver<Value>,
+) -> impl Stream<Item = Result<Value, E>> + 'a {
+    async_stream::stream! {
+        tokio::pin!(responses);
+        loop {
+            let res = tokio::select! {
+                biased;
+                Some(notification) = notifications.recv() => {
+                    yield Ok(notification);
+                    continue;
+                }
+                res = responses.next() => res,
+            };
+            // the poll that finished a request may have also queued its last notifications
+            while let Ok(notification) = notifications.try_recv() {
+                yield Ok(notification);
+            }
+            match res {
+                Some(res) => yield res,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Draws a progress bar on stderr, and erases it when dropped.
+pub(crate) struct ProgressBar {
+    drawn: Arc<Mutex<bool>>,
+}
+impl ProgressBar {
+    /// A bar, unless stderr is not a terminal.
+    pub fn for_stderr() -> Option<Self> {
+        std::io::stderr().is_terminal().then(|| Self {
+            drawn: Arc::new(Mutex::new(false)),
+        })
+    }
+    pub fn reporter(&self) -> ProgressReporter {
+        let drawn = self.drawn.clone();
+        ProgressReporter::new(move |event| {
+            let mut stderr = std::io::stderr().lock();
+            write!(stderr, "\r\x1b[2K{}", render(&event)).ok();
+            stderr.flush().ok();
+            *drawn.lock().unwrap() = true;
+        })
+    }
+}
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        if *self.drawn.lock().unwrap() {
+            let mut stderr = std::io::stderr().lock();
+            write!(stderr, "\r\x1b[2K").ok();
+            stderr.flush().ok();
+        }
+    }
+}
+
+/// Draws the progress of `fut` on stderr, unless something else already receives it.
+pub(crate) async fn with_progress_bar<F: Future>(fut: F) -> F::Output {
+    if ProgressReporter::current().is_some() {
+        return fut.await;
+    }
+    match ProgressBar::for_stderr() {
+        Some(bar) => bar.reporter().scope(fut).await,
+        None => fut.await,
+    }
+}
+
+fn render(event: &ProgressEvent) -> String {
+    let mut line = if let Some(total) = event.total.filter(|t| *t > 0) {
+        let ratio = (event.done.min(total) as f64) / (total as f64);
+        let filled = (ratio * BAR_WIDTH as f64).round() as usize;
+        format!(
+            "[{}{}] {:>3}%",
+            "#".repeat(filled),
+            ".".repeat(BAR_WIDTH - filled),
+            (ratio * 100.0).round() as u64,
+        )
+    } else {
+        event.done.to_string()
+    };
+    if let Some(message) = &event.message {
+        line.push(' ');
+        line.push_str(message);
+    }
+    line
+}
@@ -0,0 +1,371 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Method};
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use url::Url;
+use yajrc::RpcError;
+
+use crate::cli::send_http;
+use crate::server::Call;
+use crate::telemetry::{instrument_call, random_u64};
+use crate::{
+    CallRemote, CliBindings, ClientError, ClientMiddleware, ClientMiddlewareChain, ClientRequest,
+    Empty, HandlerArgsFor, HandlerFor, HandlerTypes, IDEMPOTENCY_KEY, REQUEST_IN_PROGRESS_ERROR,
+};
+
+/// A reusable HTTP client for calling a remote server, meant to be embedded in a context.
+///
+/// Contexts that implement [`HttpRemoteContext`] get [`CallRemote`] for free. The underlying
+/// [`reqwest::Client`] is built on the first call and shared between clones.
+#[derive(Clone)]
+pub struct HttpRemote {
+    base_url: Url,
+    path: String,
+    headers: HeaderMap,
+    metadata_headers: Vec<(&'static str, HeaderName)>,
+    cookie_file: Option<PathBuf>,
+    retry: Option<RetryPolicy>,
+    middleware: ClientMiddlewareChain,
+    connection: Arc<OnceLock<Connection>>,
+}
+
+struct Connection {
+    client: Client,
+    cookies: Arc<CookieStoreMutex>,
+}
+
+impl HttpRemote {
+    pub fn new(base_url: Url) -> Self {
+        Self {
+            base_url,
+            path: String::new(),
+            headers: HeaderMap::new(),
+            metadata_headers: Vec::new(),
+            cookie_file: None,
+            retry: None,
+            middleware: ClientMiddlewareChain::new(),
+            connection: Default::default(),
+        }
+    }
+    /// The path of the rpc endpoint, relative to the base url.
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self.connection = Default::default();
+        self
+    }
+    /// Sends `value` as `name` on every request.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self.connection = Default::default();
+        self
+    }
+    /// Sends the handler metadata value at `key` as the `header` of a request. Strings are sent
+    /// as is, other values as JSON.
+    pub fn with_metadata_header(mut self, key: &'static str, header: HeaderName) -> Self {
+        self.metadata_headers.push((key, header));
+        self
+    }
+    /// Loads cookies from `path` if it exists, and saves them back after every call, so that a
+    /// session survives between runs of a cli.
+    pub fn with_cookie_jar(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cookie_file = Some(path.into());
+        self.connection = Default::default();
+        self
+    }
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+    /// Runs `middleware` around every attempt of a call, after the headers above are set.
+    pub fn with_middleware<M: ClientMiddleware>(mut self, middleware: M) -> Self {
+        self.middleware = self.middleware.middleware(middleware);
+        self
+    }
+    pub fn url(&self) -> Result<Url, url::ParseError> {
+        self.base_url.join(&self.path)
+    }
+
+    fn connection(&self) -> Result<&Connection, ClientError> {
+        if let Some(connection) = self.connection.get() {
+            return Ok(connection);
+        }
+        let cookies = Arc::new(CookieStoreMutex::new(match &self.cookie_file {
+            Some(path) => load_cookies(path)?,
+            None => CookieStore::default(),
+        }));
+        let client = Client::builder()
+            .default_headers(self.headers.clone())
+            .cookie_provider(cookies.clone())
+            .build()?;
+        Ok(self
+            .connection
+            .get_or_init(|| Connection { client, cookies }))
+    }
+
+    /// Calls `method`, retrying according to [`HttpRemote::with_retry`].
+    ///
+    /// Calls to methods not marked `idempotent` in their metadata carry an
+    /// [`IDEMPOTENCY_KEY`] header, which stays the same across retries.
+    pub async fn call(
+        &self,
+        method: &str,
+        metadata: OrdMap<&'static str, Value>,
+        params: Value,
+    ) -> Result<Value, ClientError> {
+        let connection = self.connection()?;
+        let url = self
+            .url()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let idempotent = metadata
+            .get("idempotent")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let key = (!idempotent).then(idempotency_key);
+        let retry = self
+            .retry
+            .as_ref()
+            .filter(|retry| idempotent || retry.retry_keyed);
+        let mut attempt = 0;
+        loop {
+            let mut headers = HeaderMap::new();
+            for (key, header) in &self.metadata_headers {
+                if let Some(value) = metadata.get(key).and_then(header_value) {
+                    headers.insert(header.clone(), value);
+                }
+            }
+            if let Some(key) = key.as_deref().and_then(|k| HeaderValue::from_str(k).ok()) {
+                headers.insert(IDEMPOTENCY_KEY, key);
+            }
+            let request = ClientRequest {
+                method: method.to_owned(),
+                params: params.clone(),
+                metadata: metadata.clone(),
+                headers,
+            };
+            let res = self
+                .middleware
+                .run(request, |req| async {
+                    let http = connection
+                        .client
+                        .request(Method::POST, url.clone())
+                        .headers(req.headers);
+                    send_http(http, &req.method, req.params).await
+                })
+                .await;
+            if let Some(path) = &self.cookie_file {
+                save_cookies(path, &connection.cookies).await?;
+            }
+            let retryable = match &res {
+                Err(ClientError::Transport(_)) => true,
+                Err(ClientError::Rpc(e)) => e.code == REQUEST_IN_PROGRESS_ERROR.code,
+                _ => false,
+            };
+            match retry {
+                Some(retry) if retryable && attempt + 1 < retry.max_attempts => {
+                    tokio::time::sleep(retry.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                _ => return res,
+            }
+        }
+    }
+}
+
+/// How [`HttpRemote`] retries calls that the server did not answer, or that an
+/// [`IdempotencyCache`](crate::IdempotencyCache) reports as still running.
+///
+/// Only methods marked `idempotent` in their metadata are retried, unless `retry_keyed` is set.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Also retry the other methods, which is only safe if the server deduplicates their
+    /// idempotency keys with an [`IdempotencyCache`](crate::IdempotencyCache).
+    pub retry_keyed: bool,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            retry_keyed: false,
+        }
+    }
+}
+impl RetryPolicy {
+    /// Exponential backoff, with the upper half of each delay randomized.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let cap = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let half = cap / 2;
+        let range = u64::try_from(half.as_nanos())
+            .unwrap_or(u64::MAX)
+            .saturating_add(1);
+        half + Duration::from_nanos(random_u64() % range)
+    }
+}
+
+fn idempotency_key() -> String {
+    format!("{:016x}{:016x}", random_u64(), random_u64())
+}
+
+/// A context that calls its remote through an [`HttpRemote`].
+pub trait HttpRemoteContext: crate::Context {
+    fn http_remote(&self) -> &HttpRemote;
+}
+
+impl<Context: HttpRemoteContext, RemoteContext> CallRemote<RemoteContext> for Context {
+    async fn call_remote(
+        &self,
+        method: &str,
+        metadata: OrdMap<&'static str, Value>,
+        params: Value,
+        _: Empty,
+    ) -> Result<Value, RpcError> {
+        Ok(self.http_remote().call(method, metadata, params).await?)
+    }
+}
+
+/// Proxies every call below the path it is mounted at to a remote server, as raw values.
+///
+/// `.subcommand("node2", remote_mount(url))` forwards `node2.status` to the remote's `status`,
+/// so a gateway can expose several backends without compiling in their handlers. The metadata
+/// of the call, e.g. `idempotent` from [`RemoteMount::with_metadata`], is forwarded along with
+/// it. Mounts only forward calls made through a [`Server`](crate::Server), and have no cli or
+/// type info.
+#[derive(Clone)]
+pub struct RemoteMount {
+    remote: HttpRemote,
+    metadata: OrdMap<&'static str, Value>,
+}
+impl RemoteMount {
+    pub fn new(remote: HttpRemote) -> Self {
+        Self {
+            remote,
+            metadata: OrdMap::new(),
+        }
+    }
+    pub fn with_metadata(mut self, key: &'static str, value: Value) -> Self {
+        self.metadata.insert(key, value);
+        self
+    }
+}
+impl std::fmt::Debug for RemoteMount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("RemoteMount")
+            .field(&self.remote.base_url.as_str())
+            .finish()
+    }
+}
+
+pub fn remote_mount(url: Url) -> RemoteMount {
+    RemoteMount::new(HttpRemote::new(url))
+}
+
+impl HandlerTypes for RemoteMount {
+    type Params = Value;
+    type InheritedParams = Empty;
+    type Ok = Value;
+    type Err = RpcError;
+}
+
+#[cfg(feature = "ts-rs")]
+impl crate::handler::HandlerTS for RemoteMount {
+    fn type_info(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<Context: crate::Context> HandlerFor<Context> for RemoteMount {
+    async fn handle_async(
+        &self,
+        handle_args: HandlerArgsFor<Context, Self>,
+    ) -> Result<Self::Ok, Self::Err> {
+        // the remote's names are not known up front, so the path ends at the mount and the rest
+        // of the method is taken from the call as the client sent it
+        let call = Call::current().ok_or(yajrc::METHOD_NOT_FOUND_ERROR)?;
+        let method = call
+            .method_below(&handle_args.parent_method)
+            .filter(|method| !method.is_empty())
+            .ok_or(yajrc::METHOD_NOT_FOUND_ERROR)?;
+        Ok(instrument_call(
+            method,
+            self.remote
+                .call(method, call.metadata.clone(), handle_args.raw_params),
+        )
+        .await?)
+    }
+    fn metadata(&self, _: VecDeque<&'static str>) -> OrdMap<&'static str, Value> {
+        self.metadata.clone()
+    }
+    fn method_from_dots(&self, method: &str) -> Option<VecDeque<&'static str>> {
+        if method.split('.').any(str::is_empty) {
+            return None;
+        }
+        Some(VecDeque::new())
+    }
+}
+
+impl<Context: crate::Context> CliBindings<Context> for RemoteMount {
+    const NO_CLI: bool = true;
+    fn cli_command(&self) -> clap::Command {
+        unimplemented!()
+    }
+    fn cli_parse(
+        &self,
+        _: &clap::ArgMatches,
+    ) -> Result<(VecDeque<&'static str>, Value), clap::Error> {
+        unimplemented!()
+    }
+    fn cli_display(&self, _: HandlerArgsFor<Context, Self>, _: Self::Ok) -> Result<(), Self::Err> {
+        unimplemented!()
+    }
+}
+
+fn header_value(value: &Value) -> Option<HeaderValue> {
+    let value = match value {
+        Value::Null => return None,
+        Value::String(s) => s.to_string(),
+        v => serde_json::to_string(v).ok()?,
+    };
+    HeaderValue::from_str(&value).ok()
+}
+
+fn load_cookies(path: &Path) -> std::io::Result<CookieStore> {
+    let in_path = |e| std::io::Error::other(format!("{}: {e}", path.display()));
+    match std::fs::File::open(path) {
+        Ok(file) => cookie_store::serde::json::load(std::io::BufReader::new(file)).map_err(in_path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(CookieStore::default()),
+        Err(e) => Err(std::io::Error::new(
+            e.kind(),
+            format!("{}: {e}", path.display()),
+        )),
+    }
+}
+
+async fn save_cookies(path: &Path, cookies: &CookieStoreMutex) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    {
+        let cookies = cookies
+            .lock()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        // session cookies are what keep a cli logged in between runs
+        cookie_store::serde::json::save_incl_expired_and_nonpersistent(&cookies, &mut buf)
+            .map_err(std::io::Error::other)?;
+    }
+    tokio::fs::write(path, buf)
+        .await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+}
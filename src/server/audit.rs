@@ -0,0 +1,235 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::time::{Instant, SystemTime};
+
+use axum::extract::{ConnectInfo, Request};
+use axum::response::Response;
+use futures::Future;
+use imbl_value::{InternedString, Value};
+use serde::{Deserialize, Serialize};
+use yajrc::{RpcError, RpcMethod};
+
+use crate::server::{RpcRequest, RpcResponse};
+use crate::util::redact;
+use crate::{Middleware, Peer};
+
+/// Audit settings, read from the `audit` and `sensitive` metadata keys of a handler.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditMetadata {
+    #[serde(default)]
+    pub audit: bool,
+    #[serde(default)]
+    pub sensitive: Vec<InternedString>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "status")]
+pub enum AuditStatus {
+    Ok,
+    Error { code: i32, message: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the unix epoch at which the request was received.
+    pub timestamp: u128,
+    pub principal: Option<InternedString>,
+    pub peer: Option<String>,
+    pub method: InternedString,
+    pub params: Value,
+    #[serde(flatten)]
+    pub status: AuditStatus,
+    pub duration_ms: f64,
+}
+
+/// Where [`AuditLog`] sends its records. `record` is called from the server's async tasks, so it
+/// should hand the record off rather than block on I/O, as [`JsonLinesSink`] does.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord) -> std::io::Result<()>;
+}
+impl<F: Fn(&AuditRecord) -> std::io::Result<()> + Send + Sync> AuditSink for F {
+    fn record(&self, record: &AuditRecord) -> std::io::Result<()> {
+        self(record)
+    }
+}
+
+/// Appends one JSON object per line to a file, syncing after every record.
+///
+/// Records are written in order by a dedicated thread. A failed write is returned by the next
+/// call to `record`.
+pub struct JsonLinesSink {
+    lines: mpsc::Sender<Vec<u8>>,
+    error: Arc<Mutex<Option<std::io::Error>>>,
+}
+impl JsonLinesSink {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (lines, rx) = mpsc::channel::<Vec<u8>>();
+        let error = Arc::new(Mutex::new(None));
+        std::thread::Builder::new()
+            .name("audit-log".into())
+            .spawn({
+                let error = error.clone();
+                move || {
+                    for line in rx {
+                        if let Err(e) = file.write_all(&line).and_then(|_| file.sync_data()) {
+                            *error.lock().unwrap_or_else(PoisonError::into_inner) = Some(e);
+                        }
+                    }
+                }
+            })?;
+        Ok(Self { lines, error })
+    }
+}
+impl AuditSink for JsonLinesSink {
+    fn record(&self, record: &AuditRecord) -> std::io::Result<()> {
+        if let Some(e) = self
+            .error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+        {
+            return Err(e);
+        }
+        let mut line = serde_json::to_vec(record).map_err(std::io::Error::other)?;
+        line.push(b'\n');
+        self.lines
+            .send(line)
+            .map_err(|_| std::io::Error::other("audit log writer stopped"))
+    }
+}
+
+type PrincipalFn<Context> =
+    Arc<dyn Fn(&Context, &RpcRequest) -> Option<InternedString> + Send + Sync>;
+type ErrorHandler = Arc<dyn Fn(std::io::Error) + Send + Sync>;
+
+#[derive(Clone)]
+struct Pending {
+    timestamp: u128,
+    start: Instant,
+    principal: Option<InternedString>,
+    method: InternedString,
+    params: Value,
+}
+
+/// Records calls to methods marked `.with_metadata("audit", Value::Bool(true))` to an
+/// [`AuditSink`]. Params named in the `sensitive` metadata list (see
+/// [`HandlerExt::with_sensitive`](crate::HandlerExt::with_sensitive)) are redacted before recording.
+///
+/// Works with both [`HttpServer::middleware`](crate::HttpServer::middleware) and
+/// [`SocketServer::middleware`](crate::SocketServer::middleware). Middleware sees requests in
+/// the reverse of the order it was added in, so add `AuditLog` last: calls rejected by
+/// middleware that runs before it are never recorded.
+///
+/// `error_handler` is called with the errors of the sink, which has no caller to return them to.
+pub struct AuditLog<Context> {
+    sink: Arc<dyn AuditSink>,
+    principal: Option<PrincipalFn<Context>>,
+    error_handler: ErrorHandler,
+    peer: Option<Peer>,
+    pending: Option<Pending>,
+}
+impl<Context> Clone for AuditLog<Context> {
+    fn clone(&self) -> Self {
+        Self {
+            sink: self.sink.clone(),
+            principal: self.principal.clone(),
+            error_handler: self.error_handler.clone(),
+            peer: self.peer.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+impl<Context> AuditLog<Context> {
+    pub fn new(
+        sink: impl AuditSink + 'static,
+        error_handler: impl Fn(std::io::Error) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            sink: Arc::new(sink),
+            principal: None,
+            error_handler: Arc::new(error_handler),
+            peer: None,
+            pending: None,
+        }
+    }
+    pub fn with_principal(
+        mut self,
+        principal: impl Fn(&Context, &RpcRequest) -> Option<InternedString> + Send + Sync + 'static,
+    ) -> Self {
+        self.principal = Some(Arc::new(principal));
+        self
+    }
+}
+
+impl<Context: crate::Context> Middleware<Context> for AuditLog<Context> {
+    type Metadata = AuditMetadata;
+    async fn process_http_request(
+        &mut self,
+        _: &Context,
+        request: &mut Request,
+    ) -> Result<(), Response> {
+        if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            self.peer = Some(Peer::Tcp(*addr));
+        }
+        Ok(())
+    }
+    async fn process_socket_connection(
+        &mut self,
+        _: &Context,
+        peer: &Peer,
+    ) -> Result<(), RpcError> {
+        self.peer = Some(peer.clone());
+        Ok(())
+    }
+    fn process_rpc_request(
+        &mut self,
+        context: &Context,
+        metadata: Self::Metadata,
+        request: &mut RpcRequest,
+    ) -> impl Future<Output = Result<(), RpcResponse>> + Send {
+        self.pending = None;
+        if metadata.audit {
+            let mut params = request.params.clone();
+            redact(&mut params, &metadata.sensitive);
+            self.pending = Some(Pending {
+                timestamp: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_millis())
+                    .unwrap_or_default(),
+                start: Instant::now(),
+                principal: self.principal.as_ref().and_then(|f| f(context, request)),
+                method: request.method.as_str().into(),
+                params,
+            });
+        }
+        async { Ok(()) }
+    }
+    async fn process_rpc_response(&mut self, _: &Context, response: &mut RpcResponse) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let record = AuditRecord {
+            timestamp: pending.timestamp,
+            principal: pending.principal,
+            peer: self.peer.as_ref().map(|p| p.to_string()),
+            method: pending.method,
+            params: pending.params,
+            status: match &response.result {
+                Ok(_) => AuditStatus::Ok,
+                Err(e) => AuditStatus::Error {
+                    code: e.code,
+                    message: e.message.to_string(),
+                },
+            },
+            duration_ms: pending.start.elapsed().as_secs_f64() * 1000.0,
+        };
+        if let Err(e) = self.sink.record(&record) {
+            (self.error_handler)(e)
+        }
+    }
+}
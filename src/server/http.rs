@@ -5,6 +5,7 @@ use axum::response::Response;
 use futures::future::{join_all, BoxFuture};
 use futures::{Future, FutureExt};
 use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use http::{HeaderMap, HeaderValue};
 use http_body_util::BodyExt;
 use imbl_value::imbl::Vector;
 use imbl_value::Value;
@@ -12,9 +13,12 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 use yajrc::{RpcError, RpcMethod};
 
-use crate::server::{RpcRequest, RpcResponse, SingleOrBatchRpcRequest};
+use crate::handler::dynamic::Snapshots;
+use crate::server::{with_data, RpcRequest, RpcResponse, SingleOrBatchRpcRequest};
+use crate::telemetry::{instrument_stage, TraceContext, TRACEPARENT};
 use crate::util::{internal_error, parse_error};
-use crate::{Context, HandleAny, Server};
+use crate::version::ClientVersion;
+use crate::{Context, HandleAny, Peer, Server, Transport, API_VERSION_HEADER, VERSION_METHOD};
 
 const FALLBACK_ERROR: &str = "{\"error\":{\"code\":-32603,\"message\":\"Internal error\",\"data\":\"Failed to serialize rpc response\"}}";
 
@@ -38,6 +42,13 @@ pub fn json_http_response<T: Serialize>(t: &T) -> Response {
         .unwrap_or_else(|_| fallback_rpc_error_response())
 }
 
+fn json_value_response(value: Result<Value, imbl_value::Error>) -> Response {
+    match value {
+        Ok(value) => json_http_response(&value),
+        Err(_) => fallback_rpc_error_response(),
+    }
+}
+
 pub trait Middleware<Context: Send + 'static>: Clone + Send + Sync + 'static {
     type Metadata: DeserializeOwned + Send + 'static;
     #[allow(unused_variables)]
@@ -49,6 +60,14 @@ pub trait Middleware<Context: Send + 'static>: Clone + Send + Sync + 'static {
         async { Ok(()) }
     }
     #[allow(unused_variables)]
+    fn process_socket_connection(
+        &mut self,
+        context: &Context,
+        peer: &Peer,
+    ) -> impl Future<Output = Result<(), RpcError>> + Send {
+        async { Ok(()) }
+    }
+    #[allow(unused_variables)]
     fn process_rpc_request(
         &mut self,
         context: &Context,
@@ -83,6 +102,11 @@ trait _Middleware<Context>: Send + Sync {
         context: &'a Context,
         request: &'a mut Request,
     ) -> BoxFuture<'a, Result<(), Response>>;
+    fn process_socket_connection<'a>(
+        &'a mut self,
+        context: &'a Context,
+        peer: &'a Peer,
+    ) -> BoxFuture<'a, Result<(), RpcError>>;
     fn process_rpc_request<'a>(
         &'a mut self,
         context: &'a Context,
@@ -112,6 +136,13 @@ impl<Context: Send + 'static, T: Middleware<Context> + Send + Sync> _Middleware<
     ) -> BoxFuture<'a, Result<(), Response>> {
         <Self as Middleware<Context>>::process_http_request(self, context, request).boxed()
     }
+    fn process_socket_connection<'a>(
+        &'a mut self,
+        context: &'a Context,
+        peer: &'a Peer,
+    ) -> BoxFuture<'a, Result<(), RpcError>> {
+        <Self as Middleware<Context>>::process_socket_connection(self, context, peer).boxed()
+    }
     fn process_rpc_request<'a>(
         &'a mut self,
         context: &'a Context,
@@ -157,6 +188,13 @@ impl<C: Context> DynMiddleware<C> {
     ) -> BoxFuture<'a, Result<(), Response>> {
         self.0.process_http_request(context, request)
     }
+    pub fn process_socket_connection<'a>(
+        &'a mut self,
+        context: &'a C,
+        peer: &'a Peer,
+    ) -> BoxFuture<'a, Result<(), RpcError>> {
+        self.0.process_socket_connection(context, peer)
+    }
     pub fn process_rpc_request<'a>(
         &'a mut self,
         context: &'a C,
@@ -208,7 +246,95 @@ impl<Context: crate::Context> Server<Context> {
     pub fn middleware<T: Middleware<Context>>(self, middleware: T) -> HttpServer<Context> {
         self.for_http().middleware(middleware)
     }
+    pub(crate) async fn process_rpc_request(
+        &self,
+        ctx: &Context,
+        mid: &mut Vector<DynMiddleware<Context>>,
+        req: RpcRequest,
+        transport: Transport,
+    ) -> RpcResponse {
+        let method = req.method.as_str().to_owned();
+        self.observe(
+            &method,
+            transport,
+            // middleware sees the metadata of the subcommands the call is dispatched to
+            Snapshots::default().scope(self.process_rpc_request_inner(ctx, mid, req, transport)),
+        )
+        .await
+    }
+    async fn process_rpc_request_inner(
+        &self,
+        ctx: &Context,
+        mid: &mut Vector<DynMiddleware<Context>>,
+        mut req: RpcRequest,
+        transport: Transport,
+    ) -> RpcResponse {
+        let metadata = if req.method.as_str() == VERSION_METHOD {
+            // the handshake is answered by the server itself, so has no handler to describe it
+            Value::Object(Default::default())
+        } else {
+            Value::Object(
+                self.root_handler
+                    .metadata(
+                        match self.root_handler.method_from_dots(req.method.as_str()) {
+                            Some(a) => a,
+                            None => {
+                                return RpcResponse {
+                                    id: req.id,
+                                    result: Err(yajrc::METHOD_NOT_FOUND_ERROR),
+                                }
+                            }
+                        },
+                    )
+                    .into_iter()
+                    .map(|(key, value)| (key.into(), value))
+                    .collect(),
+            )
+        };
+        let mut res = async {
+            if let Err(res) = instrument_stage("rpc_request", async {
+                for middleware in mid.iter_mut().rev() {
+                    middleware
+                        .process_rpc_request(ctx, metadata.clone(), &mut req)
+                        .await?;
+                }
+                Ok(())
+            })
+            .await
+            {
+                return res;
+            }
+            self.handle_single_request(req, transport).await
+        }
+        .await;
+        instrument_stage("rpc_response", async {
+            for middleware in mid.iter_mut() {
+                middleware.process_rpc_response(ctx, &mut res).await;
+            }
+        })
+        .await;
+        res
+    }
+}
+
+/// Decodes a body sent as CBOR by the cli client, or as JSON by anyone else.
+fn parse_request_body(
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<SingleOrBatchRpcRequest, RpcError> {
+    #[cfg(feature = "cbor")]
+    if headers
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|ty| ty.starts_with("application/cbor"))
+    {
+        return serde_cbor::from_slice(body).map_err(parse_error);
+    }
+    #[cfg(not(feature = "cbor"))]
+    let _ = headers;
+    serde_json::from_slice(body).map_err(parse_error)
 }
+
 impl<Context: crate::Context> HttpServer<Context> {
     pub fn middleware<T: Middleware<Context>>(mut self, middleware: T) -> Self {
         self.middleware.push_back(DynMiddleware::new(middleware));
@@ -218,50 +344,74 @@ impl<Context: crate::Context> HttpServer<Context> {
         let mut mid = self.middleware.clone();
         match async {
             let ctx = (self.inner.make_ctx)().await?;
-            for middleware in mid.iter_mut().rev() {
-                if let Err(e) = middleware.process_http_request(&ctx, &mut req).await {
-                    return Ok::<_, RpcError>(e);
+            if let Err(e) = instrument_stage("http_request", async {
+                for middleware in mid.iter_mut().rev() {
+                    middleware.process_http_request(&ctx, &mut req).await?;
                 }
-            }
-            let (_, body) = req.into_parts();
-            match serde_json::from_slice::<SingleOrBatchRpcRequest>(
-                &*body.collect().await.map_err(internal_error)?.to_bytes(),
-            )
-            .map_err(parse_error)?
+                Ok(())
+            })
+            .await
             {
+                return Ok::<_, RpcError>(e);
+            }
+            let (parts, body) = req.into_parts();
+            let body = body.collect().await.map_err(internal_error)?.to_bytes();
+            match parse_request_body(&parts.headers, &body)? {
                 SingleOrBatchRpcRequest::Single(rpc_req) => {
-                    let mut res = json_http_response(
-                        &self.process_rpc_request(&ctx, &mut mid, rpc_req).await,
-                    );
-                    for middleware in mid.iter_mut() {
-                        middleware.process_http_response(&ctx, &mut res).await;
-                    }
+                    let data = self.inner.response_data(rpc_req.method.as_str());
+                    let mut res = json_value_response(with_data(
+                        &self
+                            .inner
+                            .process_rpc_request(&ctx, &mut mid, rpc_req, Transport::Http)
+                            .await,
+                        data,
+                    ));
+                    instrument_stage("http_response", async {
+                        for middleware in mid.iter_mut() {
+                            middleware.process_http_response(&ctx, &mut res).await;
+                        }
+                    })
+                    .await;
                     Ok(res)
                 }
                 SingleOrBatchRpcRequest::Batch(rpc_reqs) => {
+                    self.inner.observe_batch(rpc_reqs.len());
                     let (mids, rpc_res): (Vec<_>, Vec<_>) =
                         join_all(rpc_reqs.into_iter().map(|rpc_req| async {
                             let mut mid = mid.clone();
-                            let res = self.process_rpc_request(&ctx, &mut mid, rpc_req).await;
-                            (mid, res)
+                            let data = self.inner.response_data(rpc_req.method.as_str());
+                            let res = self
+                                .inner
+                                .process_rpc_request(&ctx, &mut mid, rpc_req, Transport::Http)
+                                .await;
+                            (mid, with_data(&res, data))
                         }))
                         .await
                         .into_iter()
                         .unzip();
-                    let mut res = json_http_response(&rpc_res);
-                    for mut mid in mids.into_iter().fold(
-                        vec![Vec::with_capacity(rpc_res.len()); mid.len()],
-                        |mut acc, x| {
-                            for (idx, middleware) in x.into_iter().enumerate() {
-                                acc[idx].push(middleware);
+                    let count = rpc_res.len();
+                    let mut res = json_value_response(
+                        rpc_res
+                            .into_iter()
+                            .collect::<Result<_, _>>()
+                            .map(Value::Array),
+                    );
+                    instrument_stage("http_response", async {
+                        for mut mid in mids.into_iter().fold(
+                            vec![Vec::with_capacity(count); mid.len()],
+                            |mut acc, x| {
+                                for (idx, middleware) in x.into_iter().enumerate() {
+                                    acc[idx].push(middleware);
+                                }
+                                acc
+                            },
+                        ) {
+                            for middleware in mid.iter_mut() {
+                                middleware.process_http_response(&ctx, &mut res).await;
                             }
-                            acc
-                        },
-                    ) {
-                        for middleware in mid.iter_mut() {
-                            middleware.process_http_response(&ctx, &mut res).await;
                         }
-                    }
+                    })
+                    .await;
                     Ok(res)
                 }
             }
@@ -275,54 +425,34 @@ impl<Context: crate::Context> HttpServer<Context> {
             }),
         }
     }
-    async fn process_rpc_request(
-        &self,
-        ctx: &Context,
-        mid: &mut Vector<DynMiddleware<Context>>,
-        mut req: RpcRequest,
-    ) -> RpcResponse {
-        let metadata = Value::Object(
-            self.inner
-                .root_handler
-                .metadata(
-                    match self
-                        .inner
-                        .root_handler
-                        .method_from_dots(req.method.as_str())
-                    {
-                        Some(a) => a,
-                        None => {
-                            return RpcResponse {
-                                id: req.id,
-                                result: Err(yajrc::METHOD_NOT_FOUND_ERROR),
-                            }
-                        }
-                    },
-                )
-                .into_iter()
-                .map(|(key, value)| (key.into(), value))
-                .collect(),
-        );
-        let mut res = async {
-            for middleware in mid.iter_mut().rev() {
-                if let Err(res) = middleware
-                    .process_rpc_request(ctx, metadata.clone(), &mut req)
-                    .await
-                {
-                    return res;
-                }
-            }
-            self.inner.handle_single_request(req).await
-        }
-        .await;
-        for middleware in mid.iter_mut() {
-            middleware.process_rpc_response(ctx, &mut res).await;
-        }
-        res
-    }
     pub fn handle(&self, req: Request) -> BoxFuture<'static, Response> {
         let server = self.clone();
-        async move { server.process_http_request(req).await }.boxed()
+        let trace_ctx = req
+            .headers()
+            .get(TRACEPARENT)
+            .and_then(|h| h.to_str().ok())
+            .and_then(TraceContext::from_traceparent)
+            .unwrap_or_else(TraceContext::new_root);
+        // an unparseable version is treated as none, rather than failing the request
+        let version = ClientVersion::new(
+            req.headers()
+                .get(API_VERSION_HEADER)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.parse().ok()),
+        );
+        let api_version = server
+            .inner
+            .api_version()
+            .and_then(|v| HeaderValue::from_str(&v.to_string()).ok());
+        trace_ctx
+            .scope(version.scope(async move {
+                let mut res = server.process_http_request(req).await;
+                if let Some(api_version) = api_version {
+                    res.headers_mut().insert(API_VERSION_HEADER, api_version);
+                }
+                res
+            }))
+            .boxed()
     }
 }
 
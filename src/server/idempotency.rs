@@ -0,0 +1,257 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, Request};
+use axum::response::Response;
+use futures::Future;
+use imbl_value::{InternedString, Value};
+use serde::Deserialize;
+use yajrc::{RpcError, RpcMethod};
+
+use crate::server::{RpcRequest, RpcResponse};
+use crate::util::PrunedMap;
+use crate::{Middleware, RateLimitKey};
+
+/// The HTTP header carrying the key that identifies retries of the same call.
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+pub const REQUEST_IN_PROGRESS_ERROR: RpcError = RpcError {
+    code: -32030,
+    message: Cow::Borrowed("Request with this idempotency key is in progress"),
+    data: None,
+};
+
+pub const IDEMPOTENCY_KEY_REUSED_ERROR: RpcError = RpcError {
+    code: -32031,
+    message: Cow::Borrowed("Idempotency key was already used with different params"),
+    data: None,
+};
+
+/// Read from the `idempotent` metadata key of a handler. Methods marked idempotent are safe to
+/// repeat, so they are retried as is rather than deduplicated.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IdempotencyMetadata {
+    #[serde(default)]
+    pub idempotent: bool,
+}
+
+/// Who sent a key, the key, the method, and which call to that method it was within the HTTP
+/// request, so that calls in a batch are told apart.
+type EntryKey = (RateLimitKey, InternedString, InternedString, usize);
+
+struct Entry {
+    at: Instant,
+    params: u64,
+    /// The call's result, or `None` while it is still running.
+    result: Option<Result<Value, RpcError>>,
+}
+
+type Entries = PrunedMap<EntryKey, Entry>;
+type PrincipalFn<Context> =
+    Arc<dyn Fn(&Context, &RpcRequest) -> Option<InternedString> + Send + Sync>;
+
+/// Replays the response to a request whose `Idempotency-Key` header was already seen within
+/// the window, instead of running the method again. Use with
+/// [`HttpServer::middleware`](crate::HttpServer::middleware). Socket calls carry no headers, so
+/// they are not deduplicated.
+///
+/// Keys are scoped to the principal returned from [`IdempotencyCache::with_principal`] if any,
+/// otherwise to the caller's IP address (requires `into_make_service_with_connect_info`). A key
+/// sent again with different params is rejected with [`IDEMPOTENCY_KEY_REUSED_ERROR`]. Calls in
+/// a batch share the request's key, and are told apart by their order among the calls to the
+/// same method.
+///
+/// Middleware sees requests in the reverse of the order it was added in, so add this first:
+/// calls rejected by middleware that runs before it are not remembered, and can be retried.
+///
+/// [`HttpRemote`](crate::HttpRemote) sends a key with every call to a method not marked
+/// `idempotent`, so that [`RetryPolicy::retry_keyed`](crate::RetryPolicy::retry_keyed) can
+/// safely retry mutations.
+pub struct IdempotencyCache<Context> {
+    entries: Arc<Mutex<Entries>>,
+    window: Duration,
+    principal: Option<PrincipalFn<Context>>,
+    peer: RateLimitKey,
+    key: Option<InternedString>,
+    /// The number of calls to each method so far in the current HTTP request.
+    calls: Arc<Mutex<HashMap<InternedString, usize>>>,
+    pending: Option<Arc<Pending>>,
+}
+impl<Context> Clone for IdempotencyCache<Context> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            window: self.window,
+            principal: self.principal.clone(),
+            peer: self.peer.clone(),
+            key: self.key.clone(),
+            calls: self.calls.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+impl<Context> IdempotencyCache<Context> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(PrunedMap::new(window))),
+            window,
+            principal: None,
+            peer: RateLimitKey::Anonymous,
+            key: None,
+            calls: Default::default(),
+            pending: None,
+        }
+    }
+    pub fn with_principal(
+        mut self,
+        principal: impl Fn(&Context, &RpcRequest) -> Option<InternedString> + Send + Sync + 'static,
+    ) -> Self {
+        self.principal = Some(Arc::new(principal));
+        self
+    }
+    /// Returns the remembered outcome of `key`, or marks it as running if there is none.
+    fn check(
+        &self,
+        key: EntryKey,
+        params: u64,
+    ) -> Result<Option<Option<Result<Value, RpcError>>>, RpcError> {
+        let now = Instant::now();
+        let window = self.window;
+        let mut entries = self.entries.lock().unwrap();
+        let entries = entries.pruned(now, |_, entry| {
+            now.saturating_duration_since(entry.at) < window
+        });
+        match entries.get(&key) {
+            Some(entry) if now.saturating_duration_since(entry.at) < window => {
+                if entry.params != params {
+                    return Err(IDEMPOTENCY_KEY_REUSED_ERROR);
+                }
+                Ok(Some(entry.result.clone()))
+            }
+            _ => {
+                entries.insert(
+                    key,
+                    Entry {
+                        at: now,
+                        params,
+                        result: None,
+                    },
+                );
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// A call marked as running. If it is dropped before its result is recorded, e.g. because the
+/// client went away, the mark is removed so that a retry runs the call again.
+struct Pending {
+    entries: Arc<Mutex<Entries>>,
+    key: EntryKey,
+    done: AtomicBool,
+}
+impl Pending {
+    fn finish(&self, result: Result<Value, RpcError>) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = entries
+            .pruned(Instant::now(), |_, _| true)
+            .get_mut(&self.key)
+        {
+            entry.at = Instant::now();
+            entry.result = Some(result);
+        }
+        self.done.store(true, Ordering::Relaxed);
+    }
+}
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !self.done.load(Ordering::Relaxed) {
+            let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+            entries
+                .pruned(Instant::now(), |_, _| true)
+                .remove(&self.key);
+        }
+    }
+}
+
+fn hash_params(params: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_vec(params)
+        .expect("imbl_value::Value always serializes")
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<Context: crate::Context> Middleware<Context> for IdempotencyCache<Context> {
+    type Metadata = IdempotencyMetadata;
+    async fn process_http_request(
+        &mut self,
+        _: &Context,
+        request: &mut Request,
+    ) -> Result<(), Response> {
+        if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            self.peer = RateLimitKey::Ip(addr.ip());
+        }
+        self.key = request
+            .headers()
+            .get(IDEMPOTENCY_KEY)
+            .and_then(|h| h.to_str().ok())
+            .map(InternedString::intern);
+        self.calls = Default::default();
+        Ok(())
+    }
+    fn process_rpc_request(
+        &mut self,
+        context: &Context,
+        metadata: Self::Metadata,
+        request: &mut RpcRequest,
+    ) -> impl Future<Output = Result<(), RpcResponse>> + Send {
+        let res = match &self.key {
+            Some(key) if !metadata.idempotent => {
+                let method = InternedString::intern(request.method.as_str());
+                let call = {
+                    let mut calls = self.calls.lock().unwrap();
+                    let count = calls.entry(method.clone()).or_default();
+                    *count += 1;
+                    *count - 1
+                };
+                let caller = self
+                    .principal
+                    .as_ref()
+                    .and_then(|f| f(context, request))
+                    .map(RateLimitKey::Principal)
+                    .unwrap_or_else(|| self.peer.clone());
+                let key = (caller, key.clone(), method, call);
+                match self.check(key.clone(), hash_params(&request.params)) {
+                    Ok(Some(res)) => Err(res.unwrap_or(Err(REQUEST_IN_PROGRESS_ERROR))),
+                    Ok(None) => {
+                        self.pending = Some(Arc::new(Pending {
+                            entries: self.entries.clone(),
+                            key,
+                            done: AtomicBool::new(false),
+                        }));
+                        Ok(())
+                    }
+                    Err(e) => Err(Err(e)),
+                }
+                .map_err(|result| RpcResponse {
+                    id: request.id.clone(),
+                    result,
+                })
+            }
+            _ => Ok(()),
+        };
+        async { res }
+    }
+    async fn process_rpc_response(&mut self, _: &Context, response: &mut RpcResponse) {
+        if let Some(pending) = self.pending.take() {
+            pending.finish(response.result.clone());
+        }
+    }
+}
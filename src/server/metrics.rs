@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::handler::Handler;
+use axum::response::Response;
+use futures::future::{ready, Ready};
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use imbl_value::InternedString;
+use yajrc::RpcError;
+
+use crate::Transport;
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const BATCH_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0];
+const TRANSPORTS: [Transport; 3] = [Transport::Http, Transport::Socket, Transport::Other];
+
+/// Escapes a label value as the Prometheus text format expects: only backslashes, double
+/// quotes and line feeds.
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {count}").ok();
+        }
+        writeln!(
+            out,
+            "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}",
+            self.count
+        )
+        .ok();
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        writeln!(out, "{name}_sum{labels} {}", self.sum).ok();
+        writeln!(out, "{name}_count{labels} {}", self.count).ok();
+    }
+}
+
+#[derive(Debug)]
+struct MetricsInner {
+    requests: Mutex<BTreeMap<(InternedString, Option<i32>), u64>>,
+    latency: Mutex<BTreeMap<InternedString, Histogram>>,
+    batch_size: Mutex<Histogram>,
+    in_flight: [AtomicI64; TRANSPORTS.len()],
+    connections_open: AtomicI64,
+    connections_total: AtomicU64,
+}
+
+/// Request counters and histograms for a [`Server`](crate::Server), rendered in the
+/// Prometheus text exposition format.
+///
+/// Attach with [`Server::with_metrics`](crate::Server::with_metrics) and mount the same
+/// handle as an axum route, e.g. `.route("/metrics", get(metrics.clone()))`.
+#[derive(Debug, Clone)]
+pub struct Metrics(Arc<MetricsInner>);
+impl Metrics {
+    pub fn new() -> Self {
+        Self(Arc::new(MetricsInner {
+            requests: Mutex::new(BTreeMap::new()),
+            latency: Mutex::new(BTreeMap::new()),
+            batch_size: Mutex::new(Histogram::new(BATCH_BUCKETS)),
+            in_flight: Default::default(),
+            connections_open: AtomicI64::new(0),
+            connections_total: AtomicU64::new(0),
+        }))
+    }
+    fn transport_idx(transport: Transport) -> usize {
+        TRANSPORTS.iter().position(|t| *t == transport).unwrap_or(0)
+    }
+    pub(crate) fn request_started(&self, transport: Transport) -> InFlightGuard {
+        self.0.in_flight[Self::transport_idx(transport)].fetch_add(1, Ordering::Relaxed);
+        InFlightGuard {
+            metrics: self.clone(),
+            transport,
+            start: Instant::now(),
+        }
+    }
+    pub(crate) fn request_finished(
+        &self,
+        guard: InFlightGuard,
+        method: InternedString,
+        result: &Result<imbl_value::Value, RpcError>,
+    ) {
+        let elapsed = guard.elapsed();
+        *self
+            .0
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.clone(), result.as_ref().err().map(|e| e.code)))
+            .or_default() += 1;
+        self.0
+            .latency
+            .lock()
+            .unwrap()
+            .entry(method)
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+    pub(crate) fn batch(&self, size: usize) {
+        self.0.batch_size.lock().unwrap().observe(size as f64);
+    }
+    pub(crate) fn connection_opened(&self) -> ConnectionGuard {
+        self.0.connections_open.fetch_add(1, Ordering::Relaxed);
+        self.0.connections_total.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP rpc_requests_total Completed rpc requests.\n");
+        out.push_str("# TYPE rpc_requests_total counter\n");
+        for ((method, code), count) in self.0.requests.lock().unwrap().iter() {
+            let code = code.map(|c| c.to_string());
+            writeln!(
+                out,
+                "rpc_requests_total{{method=\"{}\",code=\"{}\"}} {count}",
+                escape_label(method),
+                code.as_deref().unwrap_or("ok"),
+            )
+            .ok();
+        }
+
+        out.push_str("# HELP rpc_request_duration_seconds Rpc request latency.\n");
+        out.push_str("# TYPE rpc_request_duration_seconds histogram\n");
+        for (method, histogram) in self.0.latency.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "rpc_request_duration_seconds",
+                &format!("method=\"{}\"", escape_label(method)),
+            );
+        }
+
+        out.push_str("# HELP rpc_requests_in_flight Rpc requests currently being handled.\n");
+        out.push_str("# TYPE rpc_requests_in_flight gauge\n");
+        for (transport, in_flight) in TRANSPORTS.iter().zip(&self.0.in_flight) {
+            writeln!(
+                out,
+                "rpc_requests_in_flight{{transport=\"{}\"}} {}",
+                transport.as_str(),
+                in_flight.load(Ordering::Relaxed),
+            )
+            .ok();
+        }
+
+        out.push_str("# HELP rpc_batch_size Number of requests per batch.\n");
+        out.push_str("# TYPE rpc_batch_size histogram\n");
+        self.0
+            .batch_size
+            .lock()
+            .unwrap()
+            .render(&mut out, "rpc_batch_size", "");
+
+        out.push_str("# HELP rpc_socket_connections Open socket connections.\n");
+        out.push_str("# TYPE rpc_socket_connections gauge\n");
+        writeln!(
+            out,
+            "rpc_socket_connections {}",
+            self.0.connections_open.load(Ordering::Relaxed)
+        )
+        .ok();
+        out.push_str("# HELP rpc_socket_connections_total Accepted socket connections.\n");
+        out.push_str("# TYPE rpc_socket_connections_total counter\n");
+        writeln!(
+            out,
+            "rpc_socket_connections_total {}",
+            self.0.connections_total.load(Ordering::Relaxed)
+        )
+        .ok();
+
+        out
+    }
+}
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handler<(), ()> for Metrics {
+    type Future = Ready<Response>;
+    fn call(self, _: Request, _: ()) -> Self::Future {
+        let body = self.render();
+        ready(
+            Response::builder()
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .header(CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+                .unwrap(),
+        )
+    }
+}
+
+pub(crate) struct InFlightGuard {
+    metrics: Metrics,
+    transport: Transport,
+    start: Instant,
+}
+impl InFlightGuard {
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.metrics.0.in_flight[Metrics::transport_idx(self.transport)]
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct ConnectionGuard(Metrics);
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0 .0.connections_open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -3,32 +3,93 @@ use std::sync::Arc;
 
 use futures::future::{join_all, BoxFuture};
 use futures::{Future, FutureExt, Stream, StreamExt};
+use imbl_value::imbl::{OrdMap, Vector};
 use imbl_value::{InternedString, Value};
+use tokio::sync::mpsc::unbounded_channel;
 use yajrc::{RpcError, RpcMethod};
 
+use crate::handler::deprecation;
+use crate::handler::dynamic::Snapshots;
+use crate::progress::{merge_notifications, notify, with_notifications};
+use crate::telemetry::instrument_request;
 use crate::util::{invalid_request, JobRunner};
-use crate::{AnyHandler, Empty, HandleAny, HandleAnyArgs, ParentHandler};
+use crate::version::{handshake, ClientVersion};
+use crate::{
+    available_in, AnyHandler, ApiVersion, Empty, HandleAny, HandleAnyArgs, ParentHandler,
+    VERSION_METHOD,
+};
 
 pub type GenericRpcMethod = yajrc::GenericRpcMethod<InternedString, Value, Value>;
 pub type RpcRequest = yajrc::RpcRequest<GenericRpcMethod>;
 pub type RpcResponse = yajrc::RpcResponse<GenericRpcMethod>;
 pub type SingleOrBatchRpcRequest = yajrc::SingleOrBatchRpcRequest<GenericRpcMethod>;
 
+pub mod audit;
 pub mod http;
+pub mod idempotency;
+pub mod metrics;
+pub mod rate_limit;
 pub mod socket;
 
+pub use audit::*;
 pub use http::*;
+pub use idempotency::*;
+pub use metrics::*;
+pub use rate_limit::*;
 pub use socket::*;
 
+tokio::task_local! {
+    static CURRENT_CALL: Call;
+}
+
+/// The call being handled by [`Server::handle_command`], for handlers such as
+/// [`RemoteMount`](crate::RemoteMount) that forward the rest of it as is.
+#[derive(Clone)]
+pub(crate) struct Call {
+    pub(crate) method: String,
+    pub(crate) metadata: OrdMap<&'static str, Value>,
+}
+impl Call {
+    pub(crate) fn current() -> Option<Self> {
+        CURRENT_CALL.try_with(|call| call.clone()).ok()
+    }
+    /// The part of the method below the handler at `parent_method`.
+    pub(crate) fn method_below(&self, parent_method: &VecDeque<&'static str>) -> Option<&str> {
+        self.method
+            .splitn(parent_method.len() + 1, '.')
+            .nth(parent_method.len())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Http,
+    Socket,
+    Other,
+}
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Socket => "socket",
+            Self::Other => "other",
+        }
+    }
+}
+
 pub struct Server<Context: crate::Context> {
     make_ctx: Arc<dyn Fn() -> BoxFuture<'static, Result<Context, RpcError>> + Send + Sync>,
     root_handler: Arc<AnyHandler<Context, Empty, ParentHandler<Context>>>,
+    metrics: Option<Metrics>,
+    api_version: Option<ApiVersion>,
 }
 impl<Context: crate::Context> Clone for Server<Context> {
     fn clone(&self) -> Self {
         Self {
             make_ctx: self.make_ctx.clone(),
             root_handler: self.root_handler.clone(),
+            metrics: self.metrics.clone(),
+            api_version: self.api_version.clone(),
         }
     }
 }
@@ -43,46 +104,141 @@ impl<Context: crate::Context> Server<Context> {
         Server {
             make_ctx: Arc::new(move || make_ctx().boxed()),
             root_handler: Arc::new(AnyHandler::new(root_handler)),
+            metrics: None,
+            api_version: None,
         }
     }
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+    /// Declares the API version this server implements. Clients that declare their own version,
+    /// through [`API_VERSION_HEADER`](crate::API_VERSION_HEADER) or [`VERSION_METHOD`], only
+    /// see the handlers [`available_in`] that version; other clients see those available in
+    /// this one.
+    pub fn with_api_version(mut self, version: ApiVersion) -> Self {
+        self.api_version = Some(version);
+        self
+    }
+    pub fn api_version(&self) -> Option<&ApiVersion> {
+        self.api_version.as_ref()
+    }
 
     pub fn handle_command(
         &self,
         method: &str,
         params: Value,
     ) -> impl Future<Output = Result<Value, RpcError>> + Send + 'static {
-        let (make_ctx, root_handler, method) = (
+        let is_handshake = method == VERSION_METHOD;
+        // resolve and dispatch against the same subcommands of any dynamic handlers
+        let snapshots = Snapshots::current_or_new();
+        let (make_ctx, root_handler, api_version, name) = (
             self.make_ctx.clone(),
             self.root_handler.clone(),
-            self.root_handler.method_from_dots(method),
+            self.api_version.clone(),
+            method.to_owned(),
         );
+        let (method, metadata) = snapshots.clone().sync_scope(|| {
+            let method = root_handler.method_from_dots(method);
+            let metadata = method.clone().map(|method| root_handler.metadata(method));
+            (method, metadata)
+        });
 
         async move {
-            root_handler
-                .handle_async(HandleAnyArgs {
-                    context: make_ctx().await?,
-                    parent_method: VecDeque::new(),
-                    method: method.ok_or_else(|| yajrc::METHOD_NOT_FOUND_ERROR)?,
-                    params,
-                    inherited: crate::Empty {},
-                })
-                .await
+            if is_handshake {
+                return handshake(api_version, params);
+            }
+            // read here rather than above, since sockets only scope the version around the
+            // returned future
+            if let (Some(version), Some(metadata)) =
+                (ClientVersion::current().or(api_version), &metadata)
+            {
+                if !available_in(metadata, &version) {
+                    return Err(RpcError {
+                        data: Some(
+                            format!("{name} is not available in API version {version}").into(),
+                        ),
+                        ..yajrc::METHOD_NOT_FOUND_ERROR
+                    });
+                }
+            }
+            #[cfg(feature = "tracing")]
+            if let Some(note) = metadata.as_ref().and_then(deprecation) {
+                tracing::warn!(rpc.method = %name, note = %note, "call to deprecated method");
+            }
+            let call = Call {
+                method: name,
+                metadata: metadata.unwrap_or_default(),
+            };
+            let handle = root_handler.handle_async(HandleAnyArgs {
+                context: make_ctx().await?,
+                parent_method: VecDeque::new(),
+                method: method.ok_or_else(|| yajrc::METHOD_NOT_FOUND_ERROR)?,
+                params,
+                inherited: crate::Empty {},
+            });
+            snapshots.scope(CURRENT_CALL.scope(call, handle)).await
         }
     }
 
-    fn handle_single_request(
+    pub(crate) fn handle_single_request(
         &self,
         RpcRequest { id, method, params }: RpcRequest,
+        transport: Transport,
     ) -> impl Future<Output = RpcResponse> + Send + 'static {
+        let span_id = id.clone();
         let handle = (|| Ok::<_, RpcError>(self.handle_command(method.as_str(), params)))();
-        async move {
+        instrument_request(method.as_str(), &span_id, transport, async move {
+            let progress = notify(&id);
             RpcResponse {
                 id,
-                result: match handle {
-                    Ok(handle) => handle.await,
-                    Err(e) => Err(e),
+                result: match (handle, progress) {
+                    (Ok(handle), Some(progress)) => progress.scope(handle).await,
+                    (Ok(handle), None) => handle.await,
+                    (Err(e), _) => Err(e),
                 },
             }
+        })
+    }
+
+    /// Records `fut` in [`Server::metrics`], labelled by `method` if it exists.
+    pub(crate) fn observe<F: Future<Output = RpcResponse>>(
+        &self,
+        method: &str,
+        transport: Transport,
+        fut: F,
+    ) -> impl Future<Output = RpcResponse> {
+        let metrics = self.metrics.clone().map(|metrics| {
+            let method = if self.root_handler.method_from_dots(method).is_some() {
+                method.into()
+            } else {
+                "unknown".into()
+            };
+            (metrics.request_started(transport), metrics, method)
+        });
+        async move {
+            let res = fut.await;
+            if let Some((guard, metrics, method)) = metrics {
+                metrics.request_finished(guard, method, &res.result);
+            }
+            res
+        }
+    }
+
+    /// The `data` member sent along with responses to `method`, which warns callers of
+    /// deprecated methods.
+    pub(crate) fn response_data(&self, method: &str) -> Option<Value> {
+        let path = self.root_handler.method_from_dots(method)?;
+        let note = deprecation(&self.root_handler.metadata(path))?;
+        Some(imbl_value::json!({ "deprecated": note }))
+    }
+
+    pub(crate) fn observe_batch(&self, size: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.batch(size);
         }
     }
 
@@ -90,42 +246,129 @@ impl<Context: crate::Context> Server<Context> {
         &self,
         request: Result<Value, RpcError>,
     ) -> BoxFuture<'static, Result<Value, imbl_value::Error>> {
-        match request.and_then(|request| {
-            imbl_value::from_value::<SingleOrBatchRpcRequest>(request).map_err(invalid_request)
-        }) {
-            Ok(SingleOrBatchRpcRequest::Single(req)) => {
-                let fut = self.handle_single_request(req);
-                async { imbl_value::to_value(&fut.await) }.boxed()
-            }
-            Ok(SingleOrBatchRpcRequest::Batch(reqs)) => {
-                let futs: Vec<_> = reqs
+        self.handle_with(&Vector::new(), request, Transport::Other)
+    }
+
+    /// Handles a single or batch request, running each call in it through `middleware`.
+    pub(crate) fn handle_with(
+        &self,
+        middleware: &Vector<DynMiddleware<Context>>,
+        request: Result<Value, RpcError>,
+        transport: Transport,
+    ) -> BoxFuture<'static, Result<Value, imbl_value::Error>> {
+        let (server, middleware) = (self.clone(), middleware.clone());
+        async move {
+            // only middleware needs a context of its own, handlers make theirs
+            let ctx = if middleware.is_empty() {
+                None
+            } else {
+                match (server.make_ctx)().await {
+                    Ok(ctx) => Some(ctx),
+                    Err(e) => {
+                        return imbl_value::to_value(&RpcResponse {
+                            id: None,
+                            result: Err(e),
+                        })
+                    }
+                }
+            };
+            match request.and_then(|request| {
+                imbl_value::from_value::<SingleOrBatchRpcRequest>(request).map_err(invalid_request)
+            }) {
+                Ok(SingleOrBatchRpcRequest::Single(req)) => {
+                    server
+                        .process_request(ctx.as_ref(), middleware, req, transport)
+                        .await
+                }
+                Ok(SingleOrBatchRpcRequest::Batch(reqs)) => {
+                    server.observe_batch(reqs.len());
+                    join_all(reqs.into_iter().map(|req| {
+                        server.process_request(ctx.as_ref(), middleware.clone(), req, transport)
+                    }))
+                    .await
                     .into_iter()
-                    .map(|req| self.handle_single_request(req))
-                    .collect();
-                async { imbl_value::to_value(&join_all(futs).await) }.boxed()
-            }
-            Err(e) => async {
-                imbl_value::to_value(&RpcResponse {
+                    .collect::<Result<_, _>>()
+                    .map(Value::Array)
+                }
+                Err(e) => imbl_value::to_value(&RpcResponse {
                     id: None,
                     result: Err(e),
-                })
+                }),
             }
-            .boxed(),
         }
+        .boxed()
+    }
+
+    async fn process_request(
+        &self,
+        ctx: Option<&Context>,
+        mut middleware: Vector<DynMiddleware<Context>>,
+        req: RpcRequest,
+        transport: Transport,
+    ) -> Result<Value, imbl_value::Error> {
+        let data = self.response_data(req.method.as_str());
+        let res = match ctx {
+            Some(ctx) => {
+                self.process_rpc_request(ctx, &mut middleware, req, transport)
+                    .await
+            }
+            None => {
+                let method = req.method.as_str().to_owned();
+                self.observe(
+                    &method,
+                    transport,
+                    self.handle_single_request(req, transport),
+                )
+                .await
+            }
+        };
+        with_data(&res, data)
     }
 
     pub fn stream<'a>(
         &'a self,
         requests: impl Stream<Item = Result<Value, RpcError>> + Send + 'a,
     ) -> impl Stream<Item = Result<Value, imbl_value::Error>> + 'a {
-        async_stream::try_stream! {
+        self.stream_with(requests, Vector::new(), Transport::Other)
+    }
+
+    /// Handles `requests` as they arrive, running each call through `middleware`, and
+    /// interleaves their responses with progress notifications.
+    pub(crate) fn stream_with<'a>(
+        &'a self,
+        requests: impl Stream<Item = Result<Value, RpcError>> + Send + 'a,
+        middleware: Vector<DynMiddleware<Context>>,
+        transport: Transport,
+    ) -> impl Stream<Item = Result<Value, imbl_value::Error>> + 'a {
+        let (notifications, notification_rx) = unbounded_channel();
+        let version = ClientVersion::default();
+        let responses = async_stream::try_stream! {
             let mut runner = JobRunner::new();
-            let requests = requests.fuse().map(|req| self.handle(req));
+            let requests = requests.fuse().map(|req| {
+                version.clone().scope(with_notifications(
+                    notifications.clone(),
+                    self.handle_with(&middleware, req, transport),
+                ))
+            });
             tokio::pin!(requests);
 
             while let Some(res) = runner.next_result(&mut requests).await.transpose()? {
                 yield res;
             }
-        }
+        };
+        merge_notifications(responses, notification_rx)
+    }
+}
+
+/// Serializes `response` with `data` as an extra member, which clients that do not know about
+/// it ignore.
+pub(crate) fn with_data(
+    response: &RpcResponse,
+    data: Option<Value>,
+) -> Result<Value, imbl_value::Error> {
+    let mut value = imbl_value::to_value(response)?;
+    if let (Some(data), Value::Object(response)) = (data, &mut value) {
+        response.insert("data".into(), data);
     }
+    Ok(value)
 }
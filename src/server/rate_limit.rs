@@ -0,0 +1,245 @@
+use std::borrow::Cow;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, Request};
+use axum::response::Response;
+use futures::Future;
+use http::header::RETRY_AFTER;
+use http::HeaderValue;
+use imbl_value::InternedString;
+use serde::{Deserialize, Deserializer, Serialize};
+use yajrc::{RpcError, RpcMethod};
+
+use crate::server::{RpcRequest, RpcResponse};
+use crate::util::PrunedMap;
+use crate::{Middleware, Peer};
+
+pub const RATE_LIMITED_ERROR: RpcError = RpcError {
+    code: -32029,
+    message: Cow::Borrowed("Rate limit exceeded"),
+    data: None,
+};
+
+/// Token bucket parameters, read from the `rate_limit` metadata key of a handler.
+///
+/// `per_sec` must be a finite number above zero, and `burst` at least one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+impl<'de> Deserialize<'de> for RateLimit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Unchecked {
+            per_sec: f64,
+            burst: u32,
+        }
+        let Unchecked { per_sec, burst } = Unchecked::deserialize(deserializer)?;
+        if !(per_sec.is_finite() && per_sec > 0.0) {
+            return Err(serde::de::Error::custom(format!(
+                "rate_limit.per_sec must be a finite number above zero, got {per_sec}"
+            )));
+        }
+        if burst < 1 {
+            return Err(serde::de::Error::custom(
+                "rate_limit.burst must be at least 1",
+            ));
+        }
+        Ok(Self { per_sec, burst })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateLimitMetadata {
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    Ip(IpAddr),
+    Uid(u32),
+    Principal(InternedString),
+    Anonymous,
+}
+impl From<&Peer> for RateLimitKey {
+    fn from(peer: &Peer) -> Self {
+        match peer {
+            Peer::Tcp(addr) => Self::Ip(addr.ip()),
+            Peer::Unix { uid, .. } => Self::Uid(*uid),
+            Peer::Unknown => Self::Anonymous,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+impl Bucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec).min(limit.burst as f64);
+        self.updated = now;
+    }
+    /// Takes a token, or returns how long until one is available.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(
+                Duration::try_from_secs_f64((1.0 - self.tokens) / limit.per_sec)
+                    .unwrap_or(Duration::MAX),
+            )
+        }
+    }
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        let mut bucket = *self;
+        bucket.refill(limit, now);
+        bucket.tokens >= limit.burst as f64
+    }
+}
+
+type Buckets = PrunedMap<(InternedString, RateLimitKey), (RateLimit, Bucket)>;
+type PrincipalFn<Context> =
+    Arc<dyn Fn(&Context, &RpcRequest) -> Option<InternedString> + Send + Sync>;
+
+/// How often buckets that have refilled since they were last used are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket rate limiter for use with [`HttpServer::middleware`](crate::HttpServer::middleware)
+/// and [`SocketServer::middleware`](crate::SocketServer::middleware).
+///
+/// Methods opt in with `.with_metadata("rate_limit", json!({ "per_sec": 1, "burst": 5 }))`.
+/// Callers are keyed by the principal returned from [`RateLimiter::with_principal`] if any,
+/// otherwise by IP address (HTTP, requires `into_make_service_with_connect_info`) or unix uid.
+pub struct RateLimiter<Context> {
+    buckets: Arc<Mutex<Buckets>>,
+    principal: Option<PrincipalFn<Context>>,
+    peer: RateLimitKey,
+    retry_after: Option<Duration>,
+}
+impl<Context> Clone for RateLimiter<Context> {
+    fn clone(&self) -> Self {
+        Self {
+            buckets: self.buckets.clone(),
+            principal: self.principal.clone(),
+            peer: self.peer.clone(),
+            retry_after: self.retry_after,
+        }
+    }
+}
+impl<Context> RateLimiter<Context> {
+    pub fn new() -> Self {
+        Self {
+            buckets: Arc::new(Mutex::new(PrunedMap::new(PRUNE_INTERVAL))),
+            principal: None,
+            peer: RateLimitKey::Anonymous,
+            retry_after: None,
+        }
+    }
+    pub fn with_principal(
+        mut self,
+        principal: impl Fn(&Context, &RpcRequest) -> Option<InternedString> + Send + Sync + 'static,
+    ) -> Self {
+        self.principal = Some(Arc::new(principal));
+        self
+    }
+    fn check(
+        &self,
+        method: InternedString,
+        key: RateLimitKey,
+        limit: RateLimit,
+    ) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // a bucket left alone long enough to refill is no different from a new one
+        let (prev, bucket) = buckets
+            .pruned(now, |_, (limit, bucket)| !bucket.is_full(limit, now))
+            .entry((method, key))
+            .or_insert_with(|| (limit, Bucket::new(&limit, now)));
+        if *prev != limit {
+            *prev = limit;
+            *bucket = Bucket::new(&limit, now);
+        }
+        bucket.take(&limit, now)
+    }
+}
+impl<Context> Default for RateLimiter<Context> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Context: crate::Context> Middleware<Context> for RateLimiter<Context> {
+    type Metadata = RateLimitMetadata;
+    async fn process_http_request(
+        &mut self,
+        _: &Context,
+        request: &mut Request,
+    ) -> Result<(), Response> {
+        if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            self.peer = RateLimitKey::Ip(addr.ip());
+        }
+        Ok(())
+    }
+    async fn process_socket_connection(
+        &mut self,
+        _: &Context,
+        peer: &Peer,
+    ) -> Result<(), RpcError> {
+        self.peer = peer.into();
+        Ok(())
+    }
+    fn process_rpc_request(
+        &mut self,
+        context: &Context,
+        metadata: Self::Metadata,
+        request: &mut RpcRequest,
+    ) -> impl Future<Output = Result<(), RpcResponse>> + Send {
+        let res = if let Some(limit) = metadata.rate_limit {
+            let key = self
+                .principal
+                .as_ref()
+                .and_then(|f| f(context, request))
+                .map(RateLimitKey::Principal)
+                .unwrap_or_else(|| self.peer.clone());
+            self.check(request.method.as_str().into(), key, limit)
+                .map_err(|retry_after| {
+                    self.retry_after = Some(retry_after);
+                    RpcResponse {
+                        id: request.id.clone(),
+                        result: Err(RpcError {
+                            data: Some(serde_json::json!({
+                                "retry_after": retry_after.as_secs_f64(),
+                            })),
+                            ..RATE_LIMITED_ERROR
+                        }),
+                    }
+                })
+        } else {
+            Ok(())
+        };
+        async { res }
+    }
+    async fn process_http_response(&mut self, _: &Context, response: &mut Response) {
+        if let Some(retry_after) = self.retry_after {
+            let secs = retry_after.as_secs_f64().ceil() as u64;
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+    }
+}
@@ -1,15 +1,19 @@
+use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
 
 use futures::{Future, Stream, StreamExt, TryStreamExt};
+use imbl_value::imbl::Vector;
 use imbl_value::Value;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, ToSocketAddrs, UnixListener};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs, UnixListener, UnixStream};
 use tokio::sync::Notify;
 use yajrc::RpcError;
 
+use crate::server::RpcResponse;
+use crate::telemetry::instrument_stage;
 use crate::util::{parse_error, JobRunner, StreamUntil};
-use crate::Server;
+use crate::{DynMiddleware, Middleware, Server, Transport};
 
 #[derive(Clone)]
 pub struct ShutdownHandle(Arc<Notify>);
@@ -19,45 +23,143 @@ impl ShutdownHandle {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix {
+        uid: u32,
+        gid: u32,
+        pid: Option<i32>,
+    },
+    Unknown,
+}
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix { uid, .. } => write!(f, "unix:uid={uid}"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+pub trait SocketPeer {
+    fn peer(&self) -> Peer {
+        Peer::Unknown
+    }
+}
+impl SocketPeer for TcpStream {
+    fn peer(&self) -> Peer {
+        self.peer_addr().map(Peer::Tcp).unwrap_or(Peer::Unknown)
+    }
+}
+impl SocketPeer for UnixStream {
+    fn peer(&self) -> Peer {
+        self.peer_cred()
+            .map(|cred| Peer::Unix {
+                uid: cred.uid(),
+                gid: cred.gid(),
+                pid: cred.pid(),
+            })
+            .unwrap_or(Peer::Unknown)
+    }
+}
+impl SocketPeer for tokio::io::DuplexStream {}
+
+fn lines(r: impl AsyncRead + Send) -> impl Stream<Item = Result<Value, RpcError>> + Send {
+    tokio_stream::wrappers::LinesStream::new(BufReader::new(r).lines())
+        .map_err(|e| RpcError {
+            data: Some(e.to_string().into()),
+            ..yajrc::INTERNAL_ERROR
+        })
+        .try_filter_map(|a| async move {
+            Ok(if a.is_empty() {
+                None
+            } else {
+                Some(serde_json::from_str::<Value>(&a).map_err(parse_error)?)
+            })
+        })
+}
+
+async fn write_responses(
+    responses: impl Stream<Item = Result<Value, imbl_value::Error>>,
+    mut w: impl AsyncWrite + Unpin,
+    error_handler: &impl Fn(std::io::Error),
+) {
+    tokio::pin!(responses);
+    while let Some(res) = responses.next().await {
+        if let Err(e) = async {
+            let mut buf = serde_json::to_vec(&res.map_err(std::io::Error::other)?)
+                .map_err(std::io::Error::other)?;
+            buf.push(b'\n');
+            w.write_all(&buf).await
+        }
+        .await
+        {
+            error_handler(e)
+        }
+    }
+}
+
 impl<Context: crate::Context> Server<Context> {
     pub fn run_socket<'a, T: AsyncRead + AsyncWrite + Send>(
         &'a self,
         listener: impl Stream<Item = std::io::Result<T>> + 'a,
         error_handler: impl Fn(std::io::Error) + Sync + 'a,
+    ) -> (ShutdownHandle, impl Future<Output = ()> + 'a) {
+        self.serve(
+            Vector::new(),
+            listener.map_ok(|pipe| (Peer::Unknown, pipe)),
+            error_handler,
+        )
+    }
+    pub fn run_unix<'a>(
+        &'a self,
+        path: impl AsRef<Path> + 'a,
+        error_handler: impl Fn(std::io::Error) + Sync + 'a,
+    ) -> std::io::Result<(ShutdownHandle, impl Future<Output = ()> + 'a)> {
+        Ok(self.run_socket(unix_listener(path)?, error_handler))
+    }
+    pub async fn run_tcp<'a>(
+        &'a self,
+        addr: impl ToSocketAddrs + 'a,
+        error_handler: impl Fn(std::io::Error) + Sync + 'a,
+    ) -> std::io::Result<(ShutdownHandle, impl Future<Output = ()> + 'a)> {
+        Ok(self.run_socket(tcp_listener(addr).await?, error_handler))
+    }
+
+    /// Serves the connections from `listener`, each with the peer it came from, until shut
+    /// down. Calls run through `middleware`, once it has accepted the peer.
+    fn serve<'a, T: AsyncRead + AsyncWrite + Send>(
+        &'a self,
+        middleware: Vector<DynMiddleware<Context>>,
+        listener: impl Stream<Item = std::io::Result<(Peer, T)>> + 'a,
+        error_handler: impl Fn(std::io::Error) + Sync + 'a,
     ) -> (ShutdownHandle, impl Future<Output = ()> + 'a) {
         let shutdown = Arc::new(Notify::new());
         (ShutdownHandle(shutdown.clone()), async move {
             let mut runner = JobRunner::<std::io::Result<()>>::new();
-            let jobs = StreamUntil::new(listener, shutdown.notified()).map(|pipe| async {
-                let pipe = pipe?;
+            let jobs = StreamUntil::new(listener, shutdown.notified()).map(|conn| async {
+                let (peer, pipe) = conn?;
+                let _conn = self.metrics.as_ref().map(|m| m.connection_opened());
                 let (r, mut w) = tokio::io::split(pipe);
-                let stream = self.stream(
-                    tokio_stream::wrappers::LinesStream::new(BufReader::new(r).lines())
-                        .map_err(|e| RpcError {
-                            data: Some(e.to_string().into()),
-                            ..yajrc::INTERNAL_ERROR
-                        })
-                        .try_filter_map(|a| async move {
-                            Ok(if a.is_empty() {
-                                None
-                            } else {
-                                Some(serde_json::from_str::<Value>(&a).map_err(parse_error)?)
-                            })
-                        }),
-                );
-                tokio::pin!(stream);
-                while let Some(res) = stream.next().await {
-                    if let Err(e) = async {
-                        let mut buf = serde_json::to_vec(
-                            &res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?,
+                match self.process_connection(middleware.clone(), &peer).await {
+                    Ok(mid) => {
+                        write_responses(
+                            self.stream_with(lines(r), mid, Transport::Socket),
+                            w,
+                            &error_handler,
                         )
-                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                        buf.push(b'\n');
-                        w.write_all(&buf).await
+                        .await;
                     }
-                    .await
-                    {
-                        error_handler(e)
+                    Err(e) => {
+                        let mut buf = serde_json::to_vec(&RpcResponse {
+                            id: None,
+                            result: Err(e),
+                        })
+                        .map_err(std::io::Error::other)?;
+                        buf.push(b'\n');
+                        w.write_all(&buf).await?;
                     }
                 }
                 Ok(())
@@ -70,26 +172,90 @@ impl<Context: crate::Context> Server<Context> {
             }
         })
     }
+    async fn process_connection(
+        &self,
+        mut mid: Vector<DynMiddleware<Context>>,
+        peer: &Peer,
+    ) -> Result<Vector<DynMiddleware<Context>>, RpcError> {
+        if mid.is_empty() {
+            return Ok(mid);
+        }
+        let ctx = (self.make_ctx)().await?;
+        instrument_stage("socket_connection", async {
+            for middleware in mid.iter_mut().rev() {
+                middleware.process_socket_connection(&ctx, peer).await?;
+            }
+            Ok::<_, RpcError>(())
+        })
+        .await?;
+        Ok(mid)
+    }
+}
+
+fn unix_listener(
+    path: impl AsRef<Path>,
+) -> std::io::Result<impl Stream<Item = std::io::Result<UnixStream>>> {
+    Ok(tokio_stream::wrappers::UnixListenerStream::new(
+        UnixListener::bind(path)?,
+    ))
+}
+
+async fn tcp_listener(
+    addr: impl ToSocketAddrs,
+) -> std::io::Result<impl Stream<Item = std::io::Result<TcpStream>>> {
+    Ok(tokio_stream::wrappers::TcpListenerStream::new(
+        TcpListener::bind(addr).await?,
+    ))
+}
+
+pub struct SocketServer<Context: crate::Context> {
+    inner: Server<Context>,
+    middleware: Vector<DynMiddleware<Context>>,
+}
+impl<Context: crate::Context> Clone for SocketServer<Context> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            middleware: self.middleware.clone(),
+        }
+    }
+}
+impl<Context: crate::Context> Server<Context> {
+    pub fn for_socket(self) -> SocketServer<Context> {
+        SocketServer {
+            inner: self,
+            middleware: Vector::new(),
+        }
+    }
+}
+impl<Context: crate::Context> SocketServer<Context> {
+    pub fn middleware<T: Middleware<Context>>(mut self, middleware: T) -> Self {
+        self.middleware.push_back(DynMiddleware::new(middleware));
+        self
+    }
+    pub fn run_socket<'a, T: AsyncRead + AsyncWrite + SocketPeer + Send>(
+        &'a self,
+        listener: impl Stream<Item = std::io::Result<T>> + 'a,
+        error_handler: impl Fn(std::io::Error) + Sync + 'a,
+    ) -> (ShutdownHandle, impl Future<Output = ()> + 'a) {
+        self.inner.serve(
+            self.middleware.clone(),
+            listener.map_ok(|pipe| (pipe.peer(), pipe)),
+            error_handler,
+        )
+    }
     pub fn run_unix<'a>(
         &'a self,
         path: impl AsRef<Path> + 'a,
         error_handler: impl Fn(std::io::Error) + Sync + 'a,
     ) -> std::io::Result<(ShutdownHandle, impl Future<Output = ()> + 'a)> {
-        let listener = UnixListener::bind(path)?;
-        Ok(self.run_socket(
-            tokio_stream::wrappers::UnixListenerStream::new(listener),
-            error_handler,
-        ))
+        Ok(self.run_socket(unix_listener(path)?, error_handler))
     }
     pub async fn run_tcp<'a>(
         &'a self,
         addr: impl ToSocketAddrs + 'a,
         error_handler: impl Fn(std::io::Error) + Sync + 'a,
     ) -> std::io::Result<(ShutdownHandle, impl Future<Output = ()> + 'a)> {
-        let listener = TcpListener::bind(addr).await?;
-        Ok(self.run_socket(
-            tokio_stream::wrappers::TcpListenerStream::new(listener),
-            error_handler,
-        ))
+        Ok(self.run_socket(tcp_listener(addr).await?, error_handler))
     }
 }
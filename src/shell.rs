@@ -0,0 +1,188 @@
+use std::path::PathBuf;
+
+use clap::error::ErrorKind;
+use clap::ArgMatches;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+use yajrc::RpcError;
+
+use crate::completion::complete_line;
+use crate::util::internal_error;
+
+pub(crate) const SHELL_COMMAND: &str = "shell";
+const EXIT_COMMANDS: &[&str] = &["exit", "quit"];
+
+pub(crate) fn shell_command() -> clap::Command {
+    clap::Command::new(SHELL_COMMAND).about("Start an interactive shell")
+}
+
+struct ShellHelper<Context> {
+    cmd: clap::Command,
+    context: Context,
+}
+impl<Context: crate::Context + Clone> Completer for ShellHelper<Context> {
+    type Candidate = String;
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete_line(&self.cmd, self.context.clone(), line, pos))
+    }
+}
+impl<Context> Hinter for ShellHelper<Context> {
+    type Hint = String;
+}
+impl<Context> Highlighter for ShellHelper<Context> {}
+impl<Context> Validator for ShellHelper<Context> {}
+impl<Context: crate::Context + Clone> Helper for ShellHelper<Context> {}
+
+/// Splits `line` into words as a POSIX shell would, and parses them with `cmd`.
+fn parse_line(cmd: &clap::Command, line: &str) -> Result<ArgMatches, clap::Error> {
+    let mut cmd = cmd.clone();
+    let words = shlex::split(line)
+        .ok_or_else(|| clap::Error::raw(ErrorKind::InvalidValue, "unterminated quote\n"))
+        .map_err(|e| e.with_cmd(&cmd))?;
+    cmd.try_get_matches_from_mut(words)
+}
+
+/// Reads commands from an interactive prompt and parses them with the app's command tree.
+pub(crate) struct Shell<Context: crate::Context + Clone> {
+    editor: Option<Editor<ShellHelper<Context>, FileHistory>>,
+    cmd: clap::Command,
+    prompt: String,
+    history: Option<PathBuf>,
+}
+impl<Context: crate::Context + Clone> Shell<Context> {
+    /// `cmd` must not expect a binary name: each line is parsed as if it followed it.
+    pub fn new(
+        cmd: clap::Command,
+        prompt: String,
+        context: Context,
+        history: Option<PathBuf>,
+    ) -> Result<Self, RpcError> {
+        let cmd = cmd.no_binary_name(true).subcommand_required(true);
+        let mut editor = Editor::new().map_err(internal_error)?;
+        editor.set_helper(Some(ShellHelper {
+            cmd: cmd.clone(),
+            context,
+        }));
+        if let Some(path) = &history {
+            // a missing history file is expected on first use
+            editor.load_history(path).ok();
+        }
+        Ok(Self {
+            editor: Some(editor),
+            cmd,
+            prompt,
+            history,
+        })
+    }
+    /// Blocks until a command is entered, returning `None` once the user exits. The inner error
+    /// is a line that could not be parsed, after which the shell can carry on.
+    pub fn next(&mut self) -> Result<Option<Result<ArgMatches, clap::Error>>, RpcError> {
+        let editor = self
+            .editor
+            .as_mut()
+            .ok_or_else(|| internal_error("shell editor missing"))?;
+        loop {
+            let line = match editor.readline(&self.prompt) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(internal_error(e)),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            editor.add_history_entry(line).map_err(internal_error)?;
+            if EXIT_COMMANDS.contains(&line) && self.cmd.find_subcommand(line).is_none() {
+                break;
+            }
+            return Ok(Some(parse_line(&self.cmd, line)));
+        }
+        if let Some(path) = &self.history {
+            editor.save_history(path).map_err(internal_error)?;
+        }
+        Ok(None)
+    }
+    /// Like [`Shell::next`], but waits for input on a blocking thread.
+    pub async fn next_async(
+        &mut self,
+    ) -> Result<Option<Result<ArgMatches, clap::Error>>, RpcError> {
+        let mut shell = Self {
+            editor: self.editor.take(),
+            cmd: self.cmd.clone(),
+            prompt: self.prompt.clone(),
+            history: self.history.clone(),
+        };
+        let (editor, res) = tokio::task::spawn_blocking(move || {
+            let res = shell.next();
+            (shell.editor.take(), res)
+        })
+        .await
+        .map_err(internal_error)?;
+        self.editor = editor;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction, Command};
+
+    use super::*;
+
+    fn cmd() -> Command {
+        Command::new("app")
+            .no_binary_name(true)
+            .subcommand_required(true)
+            .subcommand(
+                Command::new("echo")
+                    .arg(Arg::new("words").num_args(0..))
+                    .arg(Arg::new("loud").long("loud").action(ArgAction::SetTrue)),
+            )
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        let matches = parse_line(&cmd(), line).unwrap();
+        let (name, matches) = matches.subcommand().unwrap();
+        assert_eq!(name, "echo");
+        matches
+            .get_many::<String>("words")
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_line_quoting() {
+        assert_eq!(words("echo a  b"), ["a", "b"]);
+        assert_eq!(words("echo 'a b' \"c d\""), ["a b", "c d"]);
+        assert_eq!(words(r#"echo "say \"hi\"" it\'s"#), ["say \"hi\"", "it's"]);
+        assert_eq!(words("echo '' x"), ["", "x"]);
+        assert_eq!(words("echo a#b # comment"), ["a#b"]);
+        let matches = parse_line(&cmd(), "echo '--loud' x").unwrap();
+        let matches = matches.subcommand_matches("echo").unwrap();
+        assert!(matches.get_flag("loud"));
+    }
+
+    #[test]
+    fn test_parse_line_errors() {
+        for line in ["echo 'a b", "echo \"a b", "echo a\\"] {
+            let err = parse_line(&cmd(), line).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidValue, "{}", line);
+            assert!(err.to_string().contains("unterminated quote"), "{}", err);
+        }
+        let err = parse_line(&cmd(), "nope").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidSubcommand);
+    }
+}
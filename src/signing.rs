@@ -0,0 +1,237 @@
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::response::Response;
+use futures::Future;
+use hmac::{Hmac, Mac};
+use http::header::CONTENT_TYPE;
+use http::{HeaderMap, HeaderValue};
+use http_body_util::BodyExt;
+use imbl_value::Value;
+use sha2::Sha256;
+use yajrc::{RpcError, RpcMethod};
+
+use crate::server::{RpcRequest, RpcResponse};
+use crate::telemetry::random_u64;
+use crate::util::{internal_error, PrunedMap};
+use crate::{json_http_response, ClientError, ClientMiddleware, ClientRequest, Empty, Middleware};
+
+/// The HTTP header carrying the unix time, in seconds, at which a call was signed.
+pub const SIGNATURE_TIMESTAMP: &str = "x-rpc-timestamp";
+/// The HTTP header carrying a random value that makes each signature unique.
+pub const SIGNATURE_NONCE: &str = "x-rpc-nonce";
+/// The HTTP header carrying the hex encoded HMAC-SHA256 of a call.
+pub const SIGNATURE: &str = "x-rpc-signature";
+
+pub const INVALID_SIGNATURE_ERROR: RpcError = RpcError {
+    code: -32050,
+    message: Cow::Borrowed("Invalid request signature"),
+    data: None,
+};
+
+/// The signature of `method` called with `params` at `timestamp`.
+///
+/// The parts are signed as a JSON array, so that none of them can be shifted into another.
+fn sign(key: &[u8], timestamp: u64, nonce: &str, method: &str, params: &Value) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    let message = serde_json::to_vec(&(timestamp, nonce, method, params))
+        .expect("imbl_value::Value always serializes");
+    mac.update(&message);
+    mac
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Signs every call with a key shared with the server's [`HmacVerifier`]. Use with
+/// [`HttpRemote::with_middleware`](crate::HttpRemote::with_middleware).
+///
+/// The signature travels in HTTP headers, so socket calls go out unsigned.
+#[derive(Clone)]
+pub struct HmacSigner {
+    key: Arc<[u8]>,
+}
+impl HmacSigner {
+    pub fn new(key: impl AsRef<[u8]>) -> Self {
+        Self {
+            key: key.as_ref().into(),
+        }
+    }
+}
+
+impl ClientMiddleware for HmacSigner {
+    async fn process_request(&mut self, request: &mut ClientRequest) -> Result<(), ClientError> {
+        let timestamp = unix_time();
+        let nonce = format!("{:016x}{:016x}", random_u64(), random_u64());
+        let signature = sign(
+            &self.key,
+            timestamp,
+            &nonce,
+            &request.method,
+            &request.params,
+        )
+        .finalize()
+        .into_bytes();
+        request
+            .headers
+            .insert(SIGNATURE_TIMESTAMP, HeaderValue::from(timestamp));
+        request.headers.insert(
+            SIGNATURE_NONCE,
+            HeaderValue::from_str(&nonce).map_err(ClientError::protocol)?,
+        );
+        request.headers.insert(
+            SIGNATURE,
+            HeaderValue::from_str(&hex(&signature)).map_err(ClientError::protocol)?,
+        );
+        Ok(())
+    }
+}
+
+/// Rejects calls that were not signed by an [`HmacSigner`] with the same key, that were signed
+/// more than `window` ago, or whose nonce was already used. Use with
+/// [`HttpServer::middleware`](crate::HttpServer::middleware).
+///
+/// A signature covers a single call sent over HTTP, so batches, which share one set of headers,
+/// and socket calls, which have none, are always rejected.
+///
+/// Servers and clients need clocks that agree to within the window.
+#[derive(Clone)]
+pub struct HmacVerifier {
+    key: Arc<[u8]>,
+    window: Duration,
+    seen: Arc<Mutex<PrunedMap<String, Instant>>>,
+    signature: Result<Signature, &'static str>,
+}
+impl HmacVerifier {
+    pub fn new(key: impl AsRef<[u8]>, window: Duration) -> Self {
+        Self {
+            key: key.as_ref().into(),
+            window,
+            seen: Arc::new(Mutex::new(PrunedMap::new(window))),
+            signature: Err("missing signature"),
+        }
+    }
+    fn verify(&self, request: &RpcRequest) -> Result<(), &'static str> {
+        let signature = self.signature.as_ref().map_err(|reason| *reason)?;
+        if unix_time().abs_diff(signature.timestamp) > self.window.as_secs() {
+            return Err("signature expired");
+        }
+        sign(
+            &self.key,
+            signature.timestamp,
+            &signature.nonce,
+            request.method.as_str(),
+            &request.params,
+        )
+        .verify_slice(&signature.mac)
+        .map_err(|_| "signature mismatch")?;
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        // twice the window, since timestamps may be that far apart and still accepted
+        let seen = seen.pruned(now, |_, at| {
+            now.saturating_duration_since(*at) < self.window * 2
+        });
+        if seen.insert(signature.nonce.clone(), now).is_some() {
+            return Err("nonce already used");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Signature {
+    timestamp: u64,
+    nonce: String,
+    mac: Vec<u8>,
+}
+impl Signature {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = |name| headers.get(name).and_then(|h| h.to_str().ok());
+        Some(Self {
+            timestamp: header(SIGNATURE_TIMESTAMP)?.parse().ok()?,
+            nonce: header(SIGNATURE_NONCE)?.to_owned(),
+            mac: unhex(header(SIGNATURE)?)?,
+        })
+    }
+}
+
+impl<Context: crate::Context> Middleware<Context> for HmacVerifier {
+    type Metadata = Empty;
+    async fn process_http_request(
+        &mut self,
+        _: &Context,
+        request: &mut Request,
+    ) -> Result<(), Response> {
+        let body = std::mem::take(request.body_mut())
+            .collect()
+            .await
+            .map_err(|e| {
+                json_http_response(&RpcResponse {
+                    id: None,
+                    result: Err(internal_error(e)),
+                })
+            })?
+            .to_bytes();
+        self.signature = if is_batch(request.headers(), &body) {
+            Err("batches cannot be signed")
+        } else {
+            Signature::from_headers(request.headers()).ok_or("missing signature")
+        };
+        *request.body_mut() = Body::from(body);
+        Ok(())
+    }
+    async fn process_socket_connection(
+        &mut self,
+        _: &Context,
+        _: &crate::Peer,
+    ) -> Result<(), RpcError> {
+        self.signature = Err("socket calls cannot be signed");
+        Ok(())
+    }
+    fn process_rpc_request(
+        &mut self,
+        _: &Context,
+        _: Self::Metadata,
+        request: &mut RpcRequest,
+    ) -> impl Future<Output = Result<(), RpcResponse>> + Send {
+        let res = self.verify(request).map_err(|reason| RpcResponse {
+            id: request.id.clone(),
+            result: Err(RpcError {
+                data: Some(reason.into()),
+                ..INVALID_SIGNATURE_ERROR
+            }),
+        });
+        async { res }
+    }
+}
+
+/// Whether a request body holds a batch of calls: a JSON array, or a CBOR one.
+fn is_batch(headers: &HeaderMap, body: &[u8]) -> bool {
+    let cbor = headers
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|ty| ty.starts_with("application/cbor"));
+    if cbor {
+        // major type 4
+        body.first().is_some_and(|b| b >> 5 == 4)
+    } else {
+        body.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[')
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
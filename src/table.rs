@@ -0,0 +1,201 @@
+use std::cmp::Ordering;
+use std::io::IsTerminal;
+
+use imbl_value::Value;
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.to_string(),
+        value => serde_json::to_string(value).unwrap_or_default(),
+    }
+}
+
+fn truncate(cell: &str, width: usize) -> String {
+    if cell.chars().count() <= width {
+        cell.to_owned()
+    } else {
+        let mut cell: String = cell.chars().take(width.saturating_sub(1)).collect();
+        cell.truncate(cell.trim_end().len());
+        cell.push('…');
+        cell
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableStyle {
+    pub max_width: Option<usize>,
+    pub color: bool,
+}
+impl TableStyle {
+    /// Truncates to the terminal width and bolds headers if stdout is a terminal and `NO_COLOR`
+    /// is unset. Otherwise renders plain, untruncated text.
+    pub fn for_stdout() -> Self {
+        if std::io::stdout().is_terminal() {
+            Self {
+                max_width: terminal_size::terminal_size().map(|(w, _)| w.0 as usize),
+                color: std::env::var_os("NO_COLOR").is_none(),
+            }
+        } else {
+            Self::default()
+        }
+    }
+}
+
+/// A table derived from a serialized result: an array of objects, a map of objects, a flat
+/// object, or an array of scalars.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+impl Table {
+    pub fn from_value(value: &Value) -> Self {
+        let mut table = Self::default();
+        match value {
+            Value::Array(values) if values.iter().all(|v| matches!(v, Value::Object(_))) => {
+                for value in values {
+                    table.push_object(None, value);
+                }
+            }
+            Value::Array(values) => {
+                table.columns.push("value".into());
+                table.rows = values.iter().map(|v| vec![cell(v)]).collect();
+            }
+            Value::Object(map) if map.values().all(|v| matches!(v, Value::Object(_))) => {
+                // named so that it cannot shadow a field of the objects
+                let mut key_column = String::from("key");
+                while map
+                    .values()
+                    .any(|v| v.as_object().is_some_and(|o| o.contains_key(&*key_column)))
+                {
+                    key_column.insert(0, '_');
+                }
+                table.columns.push(key_column);
+                for (key, value) in map.iter() {
+                    table.push_object(Some(&**key), value);
+                }
+            }
+            Value::Object(map) => {
+                table.columns = vec!["key".into(), "value".into()];
+                table.rows = map
+                    .iter()
+                    .map(|(key, value)| vec![key.to_string(), cell(value)])
+                    .collect();
+            }
+            value => {
+                table.columns.push("value".into());
+                table.rows.push(vec![cell(value)]);
+            }
+        }
+        table
+    }
+    fn push_object(&mut self, key: Option<&str>, value: &Value) {
+        let Value::Object(map) = value else {
+            return;
+        };
+        for column in map.keys() {
+            if !self.columns.iter().any(|c| c == &**column) {
+                self.columns.push(column.to_string());
+                for row in &mut self.rows {
+                    row.push(String::new());
+                }
+            }
+        }
+        let mut row = vec![String::new(); self.columns.len()];
+        let offset = if let Some(key) = key {
+            row[0] = key.to_owned();
+            1
+        } else {
+            0
+        };
+        for (idx, column) in self.columns.iter().enumerate().skip(offset) {
+            if let Some(value) = map.get(column.as_str()) {
+                row[idx] = cell(value);
+            }
+        }
+        self.rows.push(row);
+    }
+    /// Keeps only `columns`, in the order given. Unknown columns are ignored.
+    pub fn select<S: AsRef<str>>(&mut self, columns: &[S]) {
+        let idxs: Vec<usize> = columns
+            .iter()
+            .filter_map(|c| self.columns.iter().position(|col| col == c.as_ref()))
+            .collect();
+        self.columns = idxs.iter().map(|i| self.columns[*i].clone()).collect();
+        for row in &mut self.rows {
+            *row = idxs.iter().map(|i| row[*i].clone()).collect();
+        }
+    }
+    /// Sorts rows by `column`, numerically if both cells are numbers.
+    pub fn sort_by(&mut self, column: &str, reverse: bool) {
+        let Some(idx) = self.columns.iter().position(|c| c == column) else {
+            return;
+        };
+        self.rows.sort_by(|a, b| {
+            let ord = match (a[idx].parse::<f64>(), b[idx].parse::<f64>()) {
+                (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+                _ => a[idx].cmp(&b[idx]),
+            };
+            if reverse {
+                ord.reverse()
+            } else {
+                ord
+            }
+        });
+    }
+    pub fn render(&self) -> String {
+        self.render_with(&TableStyle::default())
+    }
+    pub fn render_with(&self, style: &TableStyle) -> String {
+        const SEP: usize = 2;
+        const MIN_WIDTH: usize = 4;
+
+        let mut widths: Vec<usize> = self.columns.iter().map(|c| c.chars().count()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        if let Some(max_width) = style.max_width {
+            let sep = SEP * widths.len().saturating_sub(1);
+            while widths.iter().sum::<usize>() + sep > max_width {
+                match widths.iter_mut().filter(|w| **w > MIN_WIDTH).max() {
+                    Some(widest) => *widest -= 1,
+                    None => break,
+                }
+            }
+        }
+        let mut out = String::new();
+        let mut push_row = |row: &[String], header: bool| {
+            let mut line = String::new();
+            for (idx, (cell, width)) in row.iter().zip(&widths).enumerate() {
+                if idx > 0 {
+                    line.push_str(&" ".repeat(SEP));
+                }
+                let cell = truncate(cell, *width);
+                let pad = " ".repeat(width - cell.chars().count());
+                if header && style.color {
+                    line.push_str(&format!("\x1b[1m{cell}\x1b[0m{pad}"));
+                } else {
+                    line.push_str(&cell);
+                    line.push_str(&pad);
+                }
+            }
+            out.push_str(line.trim_end());
+            out.push('\n');
+        };
+        push_row(
+            &self
+                .columns
+                .iter()
+                .map(|c| c.to_uppercase())
+                .collect::<Vec<_>>(),
+            true,
+        );
+        for row in &self.rows {
+            push_row(row, false);
+        }
+        out
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use futures::Future;
+
+pub const TRACEPARENT: &str = "traceparent";
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// A W3C `traceparent` compatible trace context, carried across
+/// [`call_remote_http`](crate::call_remote_http) hops so that a cli call and the server
+/// request it produces share a `trace_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+}
+impl TraceContext {
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: (random_u64() as u128) << 64 | random_u64() as u128,
+            span_id: random_u64(),
+            parent_span_id: None,
+        }
+    }
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id,
+            span_id: random_u64(),
+            parent_span_id: Some(self.span_id),
+        }
+    }
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|ctx| *ctx).ok()
+    }
+    /// The current context's child, or a new root if there is no current context.
+    pub fn next() -> Self {
+        Self::current()
+            .map(|ctx| ctx.child())
+            .unwrap_or_else(Self::new_root)
+    }
+    pub fn scope<F: Future>(self, fut: F) -> impl Future<Output = F::Output> {
+        CURRENT.scope(self, fut)
+    }
+    /// Parses a version `00` `traceparent` header. The ids are taken as given, and a new span is
+    /// started from them with [`TraceContext::child`] or [`TraceContext::next`].
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let is_hex = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+        let mut parts = header.trim().split('-');
+        let (Some("00"), Some(trace_id), Some(span_id), Some(flags), None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return None;
+        };
+        if !is_hex(trace_id, 32) || !is_hex(span_id, 16) || !is_hex(flags, 2) {
+            return None;
+        }
+        let ctx = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+            parent_span_id: None,
+        };
+        // all-zero ids are invalid
+        (ctx.trace_id != 0 && ctx.span_id != 0).then_some(ctx)
+    }
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+}
+
+pub(crate) fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    if let Ok(since_epoch) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(since_epoch.as_nanos());
+    }
+    hasher.finish()
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument_stage<F: Future>(
+    stage: &'static str,
+    fut: F,
+) -> impl Future<Output = F::Output> {
+    tracing::Instrument::instrument(fut, tracing::debug_span!("rpc.middleware", stage))
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument_stage<F: Future>(_: &'static str, fut: F) -> F {
+    fut
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument_request(
+    method: &str,
+    id: &Option<yajrc::Id>,
+    transport: crate::Transport,
+    fut: impl Future<Output = crate::RpcResponse> + Send + 'static,
+) -> impl Future<Output = crate::RpcResponse> + Send + 'static {
+    use tracing::field::Empty;
+    use tracing::Instrument;
+
+    let trace_ctx = TraceContext::next();
+    let span = tracing::info_span!(
+        "rpc.request",
+        rpc.method = method,
+        rpc.id = ?id,
+        rpc.transport = transport.as_str(),
+        rpc.duration_ms = Empty,
+        rpc.error_code = Empty,
+        trace_id = %format_args!("{:032x}", trace_ctx.trace_id),
+        span_id = %format_args!("{:016x}", trace_ctx.span_id),
+        parent_span_id = Empty,
+    );
+    if let Some(parent) = trace_ctx.parent_span_id {
+        span.record("parent_span_id", format_args!("{:016x}", parent));
+    }
+    async move {
+        let start = std::time::Instant::now();
+        let res = trace_ctx.scope(fut).instrument(span.clone()).await;
+        span.record("rpc.duration_ms", start.elapsed().as_secs_f64() * 1000.0);
+        if let Err(e) = &res.result {
+            span.record("rpc.error_code", e.code);
+            tracing::debug!(parent: &span, code = e.code, message = %e.message, "rpc error");
+        }
+        res
+    }
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument_request(
+    _: &str,
+    _: &Option<yajrc::Id>,
+    _: crate::Transport,
+    fut: impl Future<Output = crate::RpcResponse> + Send + 'static,
+) -> impl Future<Output = crate::RpcResponse> + Send + 'static {
+    TraceContext::next().scope(fut)
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument_call<F: Future>(method: &str, fut: F) -> impl Future<Output = F::Output> {
+    use tracing::Instrument;
+
+    let trace_ctx = TraceContext::next();
+    let span = tracing::info_span!(
+        "rpc.call",
+        rpc.method = method,
+        trace_id = %format_args!("{:032x}", trace_ctx.trace_id),
+        span_id = %format_args!("{:016x}", trace_ctx.span_id),
+    );
+    trace_ctx.scope(fut).instrument(span)
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument_call<F: Future>(_: &str, fut: F) -> impl Future<Output = F::Output> {
+    TraceContext::next().scope(fut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_traceparent_round_trip() {
+        let ctx = TraceContext::from_traceparent(HEADER).unwrap();
+        assert_eq!(ctx.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(ctx.span_id, 0x00f067aa0ba902b7);
+        assert_eq!(ctx.parent_span_id, None);
+        assert_eq!(ctx.to_traceparent(), HEADER);
+        assert_eq!(
+            TraceContext::from_traceparent(&format!(" {HEADER}\t")),
+            Some(ctx)
+        );
+    }
+
+    #[test]
+    fn test_traceparent_invalid() {
+        for header in [
+            // wrong lengths
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-00",
+            // all-zero ids
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            // unsupported versions
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            // not hex
+            "00-+bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-0g",
+            "",
+        ] {
+            assert_eq!(TraceContext::from_traceparent(header), None, "{}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_traceparent_propagation() {
+        // what the server runs a request in, given the cli's header
+        let server = TraceContext::from_traceparent(HEADER).unwrap();
+        let outgoing = server.scope(async { TraceContext::next() }).await;
+        assert_eq!(outgoing.trace_id, server.trace_id);
+        assert_eq!(outgoing.parent_span_id, Some(server.span_id));
+        assert_ne!(outgoing.span_id, server.span_id);
+
+        // the next hop sees the same trace, with the outgoing call as its parent span
+        let next_hop = TraceContext::from_traceparent(&outgoing.to_traceparent()).unwrap();
+        assert_eq!(next_hop.trace_id, server.trace_id);
+        assert_eq!(next_hop.span_id, outgoing.span_id);
+
+        assert_eq!(TraceContext::current(), None);
+        assert_ne!(TraceContext::next().trace_id, server.trace_id);
+    }
+}
@@ -1,5 +1,8 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Display};
+use std::hash::Hash;
 use std::task::Waker;
+use std::time::{Duration, Instant};
 
 use futures::future::{BoxFuture, FusedFuture};
 use futures::stream::FusedStream;
@@ -48,6 +51,140 @@ pub fn combine(v1: Value, v2: Value) -> Result<Value, imbl_value::Error> {
     Ok(Value::Object(v1))
 }
 
+pub const REDACTED: &str = "***";
+
+/// Replaces the value of every object field named in `sensitive`, at any depth, with [`REDACTED`].
+pub fn redact<S: AsRef<str>>(value: &mut Value, sensitive: &[S]) {
+    if sensitive.is_empty() {
+        return;
+    }
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if sensitive.iter().any(|s| s.as_ref() == &**key) {
+                    *value = Value::from(REDACTED);
+                } else {
+                    redact(value, sensitive);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                redact(value, sensitive);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Shortest secret [`redact_error`] looks for within longer text. Shorter ones are only redacted
+/// where they make up a whole value, since they would otherwise match unrelated words and
+/// numbers.
+const MIN_SECRET_LEN: usize = 4;
+
+/// Replaces the occurrences of `secret` in `s` that are not part of a longer word or number.
+fn replace_token(s: &str, secret: &str) -> Option<String> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut res = String::new();
+    let mut last = 0;
+    for (idx, _) in s.match_indices(secret) {
+        let end = idx + secret.len();
+        if idx < last
+            || s[..idx].chars().next_back().is_some_and(is_word)
+            || s[end..].chars().next().is_some_and(is_word)
+        {
+            continue;
+        }
+        res.push_str(&s[last..idx]);
+        res.push_str(REDACTED);
+        last = end;
+    }
+    if last == 0 {
+        return None;
+    }
+    res.push_str(&s[last..]);
+    Some(res)
+}
+
+/// Redacts the values of `sensitive` fields of `params` from the message and data of `error`,
+/// replacing them with [`REDACTED`]. Values within the data are replaced whole, and secrets of
+/// at least [`MIN_SECRET_LEN`] characters also wherever they appear as a word of a message.
+pub fn redact_error<S: AsRef<str>>(
+    mut error: RpcError,
+    params: &Value,
+    sensitive: &[S],
+) -> RpcError {
+    fn secrets<S: AsRef<str>>(
+        value: &Value,
+        sensitive: &[S],
+        matched: bool,
+        acc: &mut Vec<String>,
+    ) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter() {
+                    let matched = matched || sensitive.iter().any(|s| s.as_ref() == &**key);
+                    secrets(value, sensitive, matched, acc);
+                }
+            }
+            Value::Array(values) => {
+                for value in values.iter() {
+                    secrets(value, sensitive, matched, acc);
+                }
+            }
+            Value::String(s) if matched && !s.is_empty() => acc.push(s.to_string()),
+            Value::Number(n) if matched => acc.push(n.to_string()),
+            _ => (),
+        }
+    }
+    fn redact_message(message: &str, secrets: &[String]) -> String {
+        let mut message = message.to_owned();
+        for secret in secrets
+            .iter()
+            .filter(|s| s.chars().count() >= MIN_SECRET_LEN)
+        {
+            if let Some(redacted) = replace_token(&message, secret) {
+                message = redacted;
+            }
+        }
+        message
+    }
+    fn scrub(value: &mut serde_json::Value, secrets: &[String]) {
+        match value {
+            serde_json::Value::String(s) if secrets.contains(s) => *s = REDACTED.to_owned(),
+            serde_json::Value::String(s) => *s = redact_message(s, secrets),
+            serde_json::Value::Number(n) if secrets.contains(&n.to_string()) => {
+                *value = REDACTED.into()
+            }
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    scrub(value, secrets);
+                }
+            }
+            serde_json::Value::Object(map) => {
+                for value in map.values_mut() {
+                    scrub(value, secrets);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if sensitive.is_empty() {
+        return error;
+    }
+    let mut acc = Vec::new();
+    secrets(params, sensitive, false, &mut acc);
+    if acc.is_empty() {
+        return error;
+    }
+    error.message = redact_message(&error.message, &acc).into();
+    if let Some(data) = &mut error.data {
+        scrub(data, &acc);
+    }
+    error
+}
+
 pub fn invalid_params(e: imbl_value::Error) -> RpcError {
     RpcError {
         data: Some(e.to_string().into()),
@@ -76,6 +213,68 @@ pub fn internal_error(e: impl Display) -> RpcError {
     }
 }
 
+/// A table of per-caller state, such as rate limit buckets or seen nonces, whose stale entries
+/// are dropped at most once per `interval` rather than on every access.
+pub(crate) struct PrunedMap<K, V> {
+    entries: HashMap<K, V>,
+    interval: Duration,
+    pruned: Instant,
+}
+impl<K: Eq + Hash, V> PrunedMap<K, V> {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            interval,
+            pruned: Instant::now(),
+        }
+    }
+    /// The entries, after dropping those `keep` rejects if a prune is due.
+    pub(crate) fn pruned(
+        &mut self,
+        now: Instant,
+        keep: impl FnMut(&K, &mut V) -> bool,
+    ) -> &mut HashMap<K, V> {
+        if now.saturating_duration_since(self.pruned) >= self.interval {
+            self.entries.retain(keep);
+            self.pruned = now;
+        }
+        &mut self.entries
+    }
+}
+
+/// A param whose [`Debug`] and [`Display`] output is [`REDACTED`]. It serializes, deserializes and
+/// parses from the cli exactly like the inner value.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Secret<T>(pub T);
+impl<T> Secret<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+impl<T> std::ops::Deref for Secret<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl<T> Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+impl<T> Display for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+impl<T: std::str::FromStr> std::str::FromStr for Secret<T> {
+    type Err = T::Err;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Secret)
+    }
+}
+
 pub struct Flat<A, B>(pub A, pub B);
 impl<'de, A, B> Deserialize<'de> for Flat<A, B>
 where
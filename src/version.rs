@@ -0,0 +1,196 @@
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use futures::Future;
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use yajrc::RpcError;
+
+use crate::util::invalid_params;
+
+/// The HTTP header in which clients declare the API version they were built against, and in
+/// which servers answer with their own.
+pub const API_VERSION_HEADER: &str = "x-api-version";
+/// The method socket clients call, with `{ "version": ... }`, to declare their API version for
+/// the rest of the connection. The server answers with its own.
+pub const VERSION_METHOD: &str = "rpc.version";
+
+tokio::task_local! {
+    static REQUESTED: ClientVersion;
+    static EXCHANGE: VersionExchange;
+}
+
+/// A dotted version number such as `2` or `2.1`. Missing components count as zero, so `2` and
+/// `2.0` are equal.
+#[derive(Debug, Clone)]
+pub struct ApiVersion(Vec<u64>);
+impl ApiVersion {
+    pub fn new(components: impl IntoIterator<Item = u64>) -> Self {
+        Self(components.into_iter().collect())
+    }
+    pub fn major(&self) -> u64 {
+        self.component(0)
+    }
+    fn component(&self, idx: usize) -> u64 {
+        self.0.get(idx).copied().unwrap_or(0)
+    }
+    /// Whether a client built against `self` can talk to `server`: the major versions match,
+    /// and the server is not older than the client.
+    pub fn is_compatible_with(&self, server: &ApiVersion) -> bool {
+        self.major() == server.major() && self <= server
+    }
+}
+impl PartialEq for ApiVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for ApiVersion {}
+impl PartialOrd for ApiVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for ApiVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (0..self.0.len().max(other.0.len()))
+            .map(|idx| self.component(idx).cmp(&other.component(idx)))
+            .find(|ord| ord.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+impl FromStr for ApiVersion {
+    type Err = ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split('.')
+            .map(|c| c.parse())
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+impl Display for ApiVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, component) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{component}")?;
+        }
+        Ok(())
+    }
+}
+impl Serialize for ApiVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+impl<'de> Deserialize<'de> for ApiVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Whether a handler exists in API `version`, according to the `introduced` and `removed` keys
+/// of its metadata, e.g. `.with_metadata("introduced", "2.1".into())`. A handler is available
+/// from the version it was introduced in up to, but not including, the one it was removed in.
+///
+/// Parents pass their metadata on to their children, so marking a [`ParentHandler`] hides its
+/// whole subtree.
+///
+/// [`ParentHandler`]: crate::ParentHandler
+pub fn available_in(metadata: &OrdMap<&'static str, Value>, version: &ApiVersion) -> bool {
+    let bound = |key| match metadata.get(key)? {
+        Value::String(s) => s.parse::<ApiVersion>().ok(),
+        Value::Number(n) => n.to_string().parse().ok(),
+        _ => None,
+    };
+    bound("introduced").is_none_or(|introduced| *version >= introduced)
+        && bound("removed").is_none_or(|removed| *version < removed)
+}
+
+/// The API version declared by the client of the current request. Sockets share one between
+/// all requests on a connection, so that [`VERSION_METHOD`] applies to the calls that follow.
+#[derive(Clone, Default)]
+pub(crate) struct ClientVersion(Arc<Mutex<Option<ApiVersion>>>);
+impl ClientVersion {
+    pub(crate) fn new(version: Option<ApiVersion>) -> Self {
+        Self(Arc::new(Mutex::new(version)))
+    }
+    pub(crate) fn current() -> Option<ApiVersion> {
+        REQUESTED
+            .try_with(|v| v.0.lock().unwrap().clone())
+            .ok()
+            .flatten()
+    }
+    pub(crate) fn scope<F: Future>(self, fut: F) -> impl Future<Output = F::Output> {
+        REQUESTED.scope(self, fut)
+    }
+}
+
+/// Answers a [`VERSION_METHOD`] call.
+pub(crate) fn handshake(server: Option<ApiVersion>, params: Value) -> Result<Value, RpcError> {
+    #[derive(Deserialize)]
+    struct Params {
+        version: Option<ApiVersion>,
+    }
+    let Params { version } = imbl_value::from_value(params).map_err(invalid_params)?;
+    let _ = REQUESTED.try_with(|v| *v.0.lock().unwrap() = version);
+    Ok(imbl_value::json!({ "version": server }))
+}
+
+/// Set by [`CliApp::with_api_version`](crate::CliApp::with_api_version) while a command runs,
+/// so that [`call_remote_http`](crate::call_remote_http) and
+/// [`call_remote_socket`](crate::call_remote_socket) declare the cli's version and record the
+/// server's.
+#[derive(Clone)]
+pub(crate) struct VersionExchange {
+    client: ApiVersion,
+    server: Arc<Mutex<Option<ApiVersion>>>,
+}
+impl VersionExchange {
+    pub(crate) fn new(client: ApiVersion) -> Self {
+        Self {
+            client,
+            server: Arc::new(Mutex::new(None)),
+        }
+    }
+    pub(crate) fn current() -> Option<Self> {
+        EXCHANGE.try_with(|e| e.clone()).ok()
+    }
+    pub(crate) fn client(&self) -> &ApiVersion {
+        &self.client
+    }
+    /// Remembers the version a server answered with, warning the first time it is one the cli
+    /// is not compatible with.
+    pub(crate) fn record(&self, server: ApiVersion) {
+        let mut prev = self.server.lock().unwrap();
+        if prev.as_ref() == Some(&server) {
+            return;
+        }
+        if !self.client.is_compatible_with(&server) {
+            eprintln!(
+                "warning: server API version {server} is incompatible with this cli ({})",
+                self.client
+            );
+        }
+        *prev = Some(server);
+    }
+    pub(crate) fn sync_scope<R>(exchange: Option<Self>, f: impl FnOnce() -> R) -> R {
+        match exchange {
+            Some(exchange) => EXCHANGE.sync_scope(exchange, f),
+            None => f(),
+        }
+    }
+    pub(crate) async fn scope<F: Future>(exchange: Option<Self>, fut: F) -> F::Output {
+        match exchange {
+            Some(exchange) => EXCHANGE.scope(exchange, fut).await,
+            None => fut.await,
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::{Arc, Mutex};
+
+use axum::body::Body;
+use axum::extract::Request;
+use rpc_toolkit::{
+    from_fn, AuditLog, AuditRecord, AuditSink, AuditStatus, Context, Empty, JsonLinesSink,
+    ParentHandler, RateLimiter, Server,
+};
+use serde_json::json;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+#[tokio::test]
+async fn test_audit_log() {
+    let records = Arc::new(Mutex::new(Vec::<AuditRecord>::new()));
+    let sink = {
+        let records = records.clone();
+        move |record: &AuditRecord| {
+            records.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    };
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>(
+            "set-password",
+            from_fn(|| Ok::<_, RpcError>("ok".to_owned()))
+                .with_metadata("audit", true.into())
+                .with_metadata("sensitive", json!(["password"]).into())
+                .with_metadata("rate_limit", json!({ "per_sec": 0.001, "burst": 1 }).into()),
+        )
+        .subcommand::<TestContext, _>("status", from_fn(|| Ok::<_, RpcError>("ok".to_owned())));
+    let server = Server::new(|| async { Ok(TestContext) }, root_handler)
+        .middleware(RateLimiter::new())
+        .middleware(
+            AuditLog::new(sink, |e| panic!("{}", e)).with_principal(|_, _| Some("admin".into())),
+        );
+
+    for method in ["set-password", "status", "set-password"] {
+        server
+            .handle(
+                Request::post("/rpc")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        json!({
+                            "jsonrpc": "2.0",
+                            "id": 1,
+                            "method": method,
+                            "params": { "user": "bob", "password": "hunter2" },
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await;
+    }
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 2);
+    let record = &records[0];
+    assert_eq!(&*record.method, "set-password");
+    assert_eq!(record.principal.as_deref(), Some("admin"));
+    assert_eq!(
+        serde_json::to_value(&record.params).unwrap(),
+        json!({ "user": "bob", "password": "***" })
+    );
+    assert_eq!(record.status, AuditStatus::Ok);
+    // added last, the audit log sees calls rejected by the middleware before it
+    assert!(
+        matches!(&records[1].status, AuditStatus::Error { code, .. } if *code == rpc_toolkit::RATE_LIMITED_ERROR.code),
+        "{:?}",
+        records[1]
+    );
+}
+
+#[test]
+fn test_json_lines_sink() {
+    let path = std::env::temp_dir().join(format!("rpc-toolkit-audit-{}.jsonl", std::process::id()));
+    let sink = JsonLinesSink::open(&path).unwrap();
+    for method in ["first", "second"] {
+        sink.record(&AuditRecord {
+            timestamp: 0,
+            principal: None,
+            peer: None,
+            method: method.into(),
+            params: imbl_value::json!({}),
+            status: AuditStatus::Ok,
+            duration_ms: 0.0,
+        })
+        .unwrap();
+    }
+    drop(sink);
+    // the writer thread finishes once the sink is dropped
+    let mut lines = Vec::new();
+    for _ in 0..100 {
+        lines = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["method"].clone())
+            .collect();
+        if lines.len() == 2 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(lines, [json!("first"), json!("second")]);
+}
@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use clap::Parser;
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use rpc_toolkit::{
+    from_fn, from_fn_async, CallRemote, CliApp, CliBindings, Context, Empty, HandlerArgs,
+    HandlerExt, OutputFormat, ParentHandler, Table, TableStyle,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+/// A `hello` command whose display records what it was given, in place of printing it.
+fn hello_app(displayed: Arc<Mutex<Vec<String>>>) -> CliApp<TestContext, Empty> {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new().subcommand(
+        "hello",
+        from_fn_async(|_: TestContext| async { Ok::<_, RpcError>("hello".to_owned()) })
+            .with_custom_display_fn(move |_, res| {
+                displayed.lock().unwrap().push(res);
+                Ok(())
+            }),
+    );
+    CliApp::<TestContext, Empty>::new(|_| Ok(TestContext), root_handler)
+}
+
+#[tokio::test]
+async fn test_run_async() {
+    let displayed = Arc::new(Mutex::new(Vec::new()));
+    hello_app(displayed.clone())
+        .run_async(["app", "hello"].map(Into::into))
+        .await
+        .unwrap();
+    assert_eq!(*displayed.lock().unwrap(), ["hello"]);
+}
+
+#[tokio::test]
+async fn test_format_flag() {
+    // other formats print the result themselves, bypassing the handler's display
+    let displayed = Arc::new(Mutex::new(Vec::new()));
+    for format in ["json", "yaml", "table"] {
+        hello_app(displayed.clone())
+            .run_async(["app", "hello", "--format", format].map(Into::into))
+            .await
+            .unwrap();
+    }
+    hello_app(displayed.clone())
+        .run_async(["app", "hello", "--format", "human"].map(Into::into))
+        .await
+        .unwrap();
+    assert_eq!(*displayed.lock().unwrap(), ["hello"]);
+
+    let render = |format: OutputFormat, value: serde_json::Value| {
+        let mut out = Vec::new();
+        format
+            .write(&mut out, &imbl_value::to_value(&value).unwrap())
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(render(OutputFormat::Json, json!("hello")), "\"hello\"\n");
+    assert_eq!(render(OutputFormat::Human, json!("hello")), "");
+    let value = json!([{ "name": "a", "size": 1 }]);
+    assert_eq!(
+        render(OutputFormat::Json, value.clone()),
+        "[{\"name\":\"a\",\"size\":1}]\n"
+    );
+    assert_eq!(
+        render(OutputFormat::Yaml, value.clone()),
+        "- name: a\n  size: 1\n"
+    );
+    assert_eq!(render(OutputFormat::Table, value), "NAME  SIZE\na     1\n");
+}
+
+#[test]
+fn test_table() {
+    let value = imbl_value::to_value(&json!([
+        { "name": "a", "size": 1 },
+        { "name": "bcd", "tags": ["x"] },
+    ]))
+    .unwrap();
+    let table = Table::from_value(&value);
+    assert_eq!(table.columns, ["name", "size", "tags"]);
+    assert_eq!(
+        table.render(),
+        "NAME  SIZE  TAGS\na     1\nbcd         [\"x\"]\n"
+    );
+}
+
+#[test]
+fn test_table_options() {
+    let value = imbl_value::to_value(&json!({
+        "alpha": { "size": 10, "description": "first entry" },
+        "beta": { "size": 9, "description": "second entry" },
+    }))
+    .unwrap();
+    let mut table = Table::from_value(&value);
+    table.sort_by("size", false);
+    table.select(&["key", "description"]);
+    assert_eq!(
+        table.render_with(&TableStyle {
+            max_width: Some(14),
+            color: false,
+        }),
+        "KEY    DESCRI…\nbeta   second…\nalpha  first…\n"
+    );
+
+    // a field named like the key column keeps its own column
+    let value = imbl_value::to_value(&json!({ "alpha": { "key": "ssh-ed25519" } })).unwrap();
+    let table = Table::from_value(&value);
+    assert_eq!(table.columns, ["_key", "key"]);
+    assert_eq!(table.rows, [["alpha", "ssh-ed25519"]]);
+}
+
+#[tokio::test]
+async fn test_table_display() {
+    let rows = || {
+        vec![
+            json!({ "name": "b", "size": 2 }),
+            json!({ "name": "a", "size": 1 }),
+        ]
+    };
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let list = {
+        let seen = seen.clone();
+        from_fn_async(move |args: HandlerArgs<TestContext>| {
+            seen.lock().unwrap().push(args.raw_params);
+            async move { Ok::<_, RpcError>(rows()) }
+        })
+        .with_table_display()
+        .no_ts()
+    };
+    let args = ["list", "--columns", "size", "--sort", "name"];
+
+    let root_handler =
+        ParentHandler::<TestContext, Empty, Empty>::new().subcommand("list", list.clone());
+    CliApp::<TestContext, Empty>::new(|_| Ok(TestContext), root_handler)
+        .run_async(std::iter::once("app").chain(args).map(Into::into))
+        .await
+        .unwrap();
+    // the table options are for the display only
+    assert_eq!(*seen.lock().unwrap(), [Value::Object(Default::default())]);
+
+    let matches = CliBindings::<TestContext>::cli_command(&list)
+        .try_get_matches_from(args)
+        .unwrap();
+    let (_, raw_params) = CliBindings::<TestContext>::cli_parse(&list, &matches).unwrap();
+    let mut out = Vec::new();
+    list.0.write(&mut out, raw_params, &rows()).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "SIZE\n1\n2\n");
+}
+
+#[test]
+fn test_docs() {
+    let make_app = || {
+        let root_handler = ParentHandler::<TestContext, Empty, Empty>::new().subcommand(
+            "hello",
+            from_fn_async(|_: TestContext| async { Ok::<_, RpcError>("hello".to_owned()) })
+                .with_metadata("audit", imbl_value::Value::Bool(true))
+                .with_about("Say hello"),
+        );
+        CliApp::<TestContext, Empty>::new(|_| Ok(TestContext), root_handler)
+            .mutate_command(|cmd| cmd.name("app"))
+            .with_docs_command()
+    };
+
+    let md = make_app().markdown_reference();
+    assert!(md.contains("# `app`"), "{}", md);
+    assert!(md.contains("## `app hello`\n\nSay hello"), "{}", md);
+    assert!(md.contains("- `audit`: `true`"), "{}", md);
+    assert!(!md.contains("app docs"), "{}", md);
+
+    let dir = std::env::temp_dir().join(format!("rpc-toolkit-docs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let written = make_app().write_man_pages(&dir).unwrap();
+    let page = std::fs::read_to_string(dir.join("app-hello.1")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(written.iter().any(|p| p.ends_with("app.1")));
+    assert!(page.contains("Say hello"), "{}", page);
+    assert!(page.contains(".SH METADATA"), "{}", page);
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Parser)]
+#[cfg_attr(feature = "ts-rs", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+struct DeployParams {
+    #[arg(long)]
+    name: String,
+    #[arg(long, default_value_t = 1)]
+    replicas: u32,
+    #[arg(long, default_value_t = 0)]
+    max_surge: u32,
+    #[arg(skip)]
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+#[test]
+fn test_params_file() {
+    let seen = Arc::new(Mutex::new(None));
+    let app = |seen: Arc<Mutex<Option<DeployParams>>>| {
+        let root_handler = ParentHandler::<TestContext, Empty, Empty>::new().subcommand(
+            "deploy",
+            from_fn(move |_: TestContext, params: DeployParams| {
+                *seen.lock().unwrap() = Some(params);
+                Ok::<_, RpcError>(())
+            })
+            .no_display(),
+        );
+        CliApp::<TestContext, Empty>::new(|_| Ok(TestContext), root_handler)
+    };
+    let path = std::env::temp_dir().join(format!("rpc-toolkit-params-{}.yaml", std::process::id()));
+    std::fs::write(&path, "replicas: 3\nlabels:\n  tier: frontend\n").unwrap();
+    let path_arg = path.to_str().unwrap();
+
+    app(seen.clone())
+        .run(["app", "deploy", "--name", "web", "--params-file", path_arg].map(Into::into))
+        .unwrap();
+    assert_eq!(
+        seen.lock().unwrap().take(),
+        Some(DeployParams {
+            name: "web".to_owned(),
+            replicas: 3,
+            max_surge: 0,
+            labels: [("tier".to_owned(), "frontend".to_owned())].into(),
+        })
+    );
+
+    // required args can come from the file alone
+    let named = path.with_extension("json");
+    std::fs::write(&named, r#"{ "name": "api", "maxSurge": 2 }"#).unwrap();
+    let named_arg = named.to_str().unwrap();
+    app(seen.clone())
+        .run(["app", "deploy", "--params-file", named_arg].map(Into::into))
+        .unwrap();
+    assert_eq!(
+        seen.lock().unwrap().take(),
+        Some(DeployParams {
+            name: "api".to_owned(),
+            replicas: 1,
+            max_surge: 2,
+            labels: BTreeMap::new(),
+        })
+    );
+    // renamed fields set on the command line still conflict with the file
+    let res = app(seen.clone()).run(
+        [
+            "app",
+            "deploy",
+            "--max-surge",
+            "1",
+            "--params-file",
+            named_arg,
+        ]
+        .map(Into::into),
+    );
+    assert!(res.is_err());
+    // and the file has to set the ones missing from the command line
+    let res = app(seen.clone()).run(["app", "deploy", "--params-file", path_arg].map(Into::into));
+    std::fs::remove_file(&named).unwrap();
+    assert!(res.is_err());
+    assert_eq!(seen.lock().unwrap().take(), None);
+
+    let res = app(seen.clone()).run(
+        [
+            "app",
+            "deploy",
+            "--name",
+            "web",
+            "--replicas",
+            "2",
+            "--params-file",
+            path_arg,
+        ]
+        .map(Into::into),
+    );
+    std::fs::remove_file(&path).unwrap();
+    assert!(res.is_err());
+    assert_eq!(seen.lock().unwrap().take(), None);
+}
+
+struct RemoteContext;
+
+impl Context for RemoteContext {}
+
+static REMOTE_CALLS: Mutex<Vec<(String, Value)>> = Mutex::new(Vec::new());
+
+impl CallRemote<RemoteContext> for TestContext {
+    async fn call_remote(
+        &self,
+        method: &str,
+        _: OrdMap<&'static str, Value>,
+        params: Value,
+        _: Empty,
+    ) -> Result<Value, RpcError> {
+        REMOTE_CALLS
+            .lock()
+            .unwrap()
+            .push((method.to_owned(), params.clone()));
+        Ok(params)
+    }
+}
+
+#[tokio::test]
+async fn test_rpc_command() {
+    let app = || {
+        CliApp::<TestContext, Empty>::new(|_| Ok(TestContext), ParentHandler::new())
+            .with_rpc_command::<RemoteContext>()
+    };
+    app()
+        .run_async(["app", "rpc", "server.echo", r#"{"message":"hi"}"#].map(Into::into))
+        .await
+        .unwrap();
+    app()
+        .run_async(["app", "rpc", "server.time"].map(Into::into))
+        .await
+        .unwrap();
+    assert!(app()
+        .run_async(["app", "rpc", "server.echo", "{"].map(Into::into))
+        .await
+        .is_err());
+    assert_eq!(
+        *REMOTE_CALLS.lock().unwrap(),
+        [
+            (
+                "server.echo".to_owned(),
+                imbl_value::to_value(&json!({ "message": "hi" })).unwrap()
+            ),
+            ("server.time".to_owned(), Value::Object(Default::default())),
+        ]
+    );
+}
@@ -0,0 +1,275 @@
+use std::sync::{Arc, Mutex};
+
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use rpc_toolkit::reqwest::Client;
+use rpc_toolkit::{
+    call_remote_http_typed, call_remote_socket_typed, ClientError, ClientMiddleware,
+    ClientMiddlewareChain, ClientRequest, CONNECT_ERROR, PROTOCOL_ERROR,
+};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use url::Url;
+use yajrc::RpcError;
+
+fn decode_request(content_type: &str, body: &[u8]) -> serde_json::Value {
+    #[cfg(feature = "cbor")]
+    if content_type == "application/cbor" {
+        return serde_cbor::from_slice(body).unwrap();
+    }
+    assert_eq!(content_type, "application/json");
+    serde_json::from_slice(body).unwrap()
+}
+
+/// Answers a single HTTP request with the content type and body returned by `respond`.
+async fn fake_http_server(
+    respond: impl FnOnce(serde_json::Value) -> (&'static str, String) + Send + 'static,
+) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    tokio::spawn(async move {
+        let (conn, _) = listener.accept().await.unwrap();
+        let mut conn = BufReader::new(conn);
+        let mut content_length = 0;
+        let mut content_type = String::new();
+        loop {
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            let line = line.trim_end().to_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(len) = line.strip_prefix("content-length: ") {
+                content_length = len.parse().unwrap();
+            } else if let Some(ty) = line.strip_prefix("content-type: ") {
+                content_type = ty.to_owned();
+            }
+        }
+        let mut body = vec![0; content_length];
+        conn.read_exact(&mut body).await.unwrap();
+        let (content_type, body) = respond(decode_request(&content_type, &body));
+        let res = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        conn.write_all(res.as_bytes()).await.unwrap();
+    });
+    url
+}
+
+/// Answers with `response`, given the request id.
+fn json(
+    response: impl FnOnce(&serde_json::Value) -> serde_json::Value + Send + 'static,
+) -> impl FnOnce(serde_json::Value) -> (&'static str, String) + Send + 'static {
+    move |req| ("application/json", response(&req["id"]).to_string())
+}
+
+async fn call(url: Url) -> Result<Value, ClientError> {
+    call_remote_http_typed(&Client::new(), url, "echo", Value::Null).await
+}
+
+#[tokio::test]
+async fn test_client_errors() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url: Url = format!("http://{}/", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    drop(listener);
+    let err = call(url).await.unwrap_err();
+    assert!(matches!(err, ClientError::Transport(_)));
+    assert!(err.is_connect());
+    assert_eq!(RpcError::from(err).code, CONNECT_ERROR.code);
+
+    let err = call(fake_http_server(|_| ("text/html", "<h1>Bad Gateway</h1>".to_owned())).await)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Protocol(_)));
+    assert_eq!(RpcError::from(err).code, PROTOCOL_ERROR.code);
+
+    let err = call(fake_http_server(|_| ("application/json", "{\"jsonrpc\":".to_owned())).await)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Protocol(_)));
+
+    let err = call(
+        fake_http_server(json(|id| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": "Method not found" },
+            })
+        }))
+        .await,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(err, ClientError::Rpc(_)));
+    assert_eq!(RpcError::from(err).code, yajrc::METHOD_NOT_FOUND_ERROR.code);
+}
+
+#[tokio::test]
+async fn test_socket_closed() {
+    let (client, server) = tokio::io::duplex(1024);
+    drop(server);
+    let err = call_remote_socket_typed(client, "echo", Value::Null)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Transport(_)));
+}
+
+#[tokio::test]
+async fn test_http_response_validation() {
+    let url = fake_http_server(json(
+        |id| json!({ "jsonrpc": "2.0", "id": id, "result": "ok" }),
+    ));
+    assert_eq!(call(url.await).await.unwrap(), Value::from("ok"));
+
+    let url = fake_http_server(json(
+        |id| json!({ "jsonrpc": "2.0", "id": id.as_u64().unwrap() + 1, "result": "ok" }),
+    ));
+    let err = call(url.await).await.unwrap_err();
+    assert!(matches!(err, ClientError::Protocol(_)), "{}", err);
+
+    let url = fake_http_server(json(
+        |id| json!({ "jsonrpc": "1.0", "id": id, "result": "ok" }),
+    ));
+    let err = call(url.await).await.unwrap_err();
+    assert!(matches!(err, ClientError::Protocol(_)), "{}", err);
+
+    // servers answer requests they cannot parse with a null id
+    let url = fake_http_server(json(|_| {
+        json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": { "code": -32700, "message": "Parse error" },
+        })
+    }));
+    let err = call(url.await).await.unwrap_err();
+    assert!(matches!(err, ClientError::Rpc(_)), "{}", err);
+}
+
+#[tokio::test]
+async fn test_socket_response_validation() {
+    async fn call_socket(
+        response: impl FnOnce(&serde_json::Value) -> serde_json::Value + Send + 'static,
+    ) -> Result<Value, ClientError> {
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let (r, mut w) = tokio::io::split(server);
+            let mut line = String::new();
+            BufReader::new(r).read_line(&mut line).await.unwrap();
+            let req: serde_json::Value = serde_json::from_str(&line).unwrap();
+            let res = response(&req["id"]).to_string() + "\n";
+            w.write_all(res.as_bytes()).await.unwrap();
+        });
+        call_remote_socket_typed(client, "echo", Value::Null).await
+    }
+
+    let res = call_socket(|id| json!({ "jsonrpc": "2.0", "id": id, "result": "ok" })).await;
+    assert_eq!(res.unwrap(), Value::from("ok"));
+
+    let err = call_socket(|_| json!({ "jsonrpc": "2.0", "id": "other", "result": "ok" }))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Protocol(_)), "{}", err);
+
+    let err = call_socket(|id| json!({ "id": id, "result": "ok" }))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Protocol(_)), "{}", err);
+}
+
+/// Adds a token to the params of calls marked `authenticated`, and records what it saw.
+#[derive(Clone)]
+struct Auth {
+    log: Arc<Mutex<Vec<String>>>,
+    name: &'static str,
+}
+impl ClientMiddleware for Auth {
+    async fn process_request(&mut self, request: &mut ClientRequest) -> Result<(), ClientError> {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("{} request", self.name));
+        if request.metadata.get("authenticated") == Some(&Value::Bool(true)) {
+            request.params = imbl_value::json!({ "token": self.name });
+        }
+        Ok(())
+    }
+    async fn process_response(&mut self, response: &mut Result<Value, ClientError>) {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("{} response", self.name));
+        if let Err(ClientError::Rpc(e)) = response {
+            e.message = format!("{}: {}", self.name, e.message).into();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_client_middleware() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let chain = ClientMiddlewareChain::new()
+        .middleware(Auth {
+            log: log.clone(),
+            name: "inner",
+        })
+        .middleware(Auth {
+            log: log.clone(),
+            name: "outer",
+        });
+    let metadata = OrdMap::unit("authenticated", Value::Bool(true));
+
+    let url = fake_http_server(|req| {
+        let res = json!({ "jsonrpc": "2.0", "id": req["id"], "result": req["params"] });
+        ("application/json", res.to_string())
+    });
+    let res = chain
+        .call_http(
+            &Client::new(),
+            url.await,
+            "echo",
+            metadata.clone(),
+            Value::Null,
+        )
+        .await
+        .unwrap();
+    assert_eq!(res, imbl_value::json!({ "token": "inner" }));
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "outer request",
+            "inner request",
+            "inner response",
+            "outer response"
+        ]
+    );
+
+    let (client, server) = tokio::io::duplex(1024);
+    drop(server);
+    let err = chain
+        .call_socket(client, "echo", OrdMap::new(), Value::Null)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Transport(_)), "{}", err);
+
+    let url = fake_http_server(json(|id| {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": -32601, "message": "Method not found" },
+        })
+    }));
+    let err = chain
+        .call_http(&Client::new(), url.await, "echo", metadata, Value::Null)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        RpcError::from(err).message,
+        "outer: inner: Method not found"
+    );
+}
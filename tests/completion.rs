@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::Parser;
+use rpc_toolkit::{from_fn, CliApp, Context, Empty, HandlerExt, ParentHandler, COMPLETE_VAR};
+use serde::{Deserialize, Serialize};
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+#[derive(Debug, Deserialize, Serialize, Parser)]
+#[cfg_attr(feature = "ts-rs", derive(ts_rs::TS))]
+struct InstallParams {
+    id: String,
+}
+
+static COMPLETED: AtomicBool = AtomicBool::new(false);
+
+fn app() -> CliApp<TestContext, Empty> {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new().subcommand(
+        "install",
+        from_fn(|_: TestContext, params: InstallParams| Ok::<_, RpcError>(params.id))
+            .with_completer("id", |_: TestContext| async {
+                COMPLETED.store(true, Ordering::SeqCst);
+                Ok(vec!["pkg-a".to_owned(), "pkg-b".to_owned()])
+            }),
+    );
+    CliApp::new(|_| Ok(TestContext), root_handler)
+}
+
+#[test]
+fn test_completions() {
+    app()
+        .run(["app", "completions", "bash"].map(Into::into))
+        .unwrap();
+    let script = app().completion_script(clap_complete::Shell::Bash).unwrap();
+    assert!(script.contains("install"), "{}", script);
+
+    std::env::set_var(COMPLETE_VAR, "bash");
+    std::env::set_var("_CLAP_COMPLETE_INDEX", "2");
+    let res = app().run(["app", "--", "app", "install", ""].map(Into::into));
+    std::env::remove_var(COMPLETE_VAR);
+    std::env::remove_var("_CLAP_COMPLETE_INDEX");
+    res.unwrap();
+    assert!(COMPLETED.load(Ordering::SeqCst));
+}
@@ -0,0 +1,100 @@
+use std::sync::{Arc, Mutex};
+
+use clap::Parser;
+use rpc_toolkit::{from_fn, CliApp, Context, HandlerExt, ParentHandler};
+use serde::{Deserialize, Serialize};
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext {
+    host: String,
+}
+
+impl Context for TestContext {}
+
+#[derive(Parser)]
+struct Config {
+    #[arg(long)]
+    host: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Parser)]
+#[cfg_attr(feature = "ts-rs", derive(ts_rs::TS))]
+struct InstallParams {
+    #[arg(long)]
+    version: String,
+}
+
+type Seen = Arc<Mutex<Option<(String, String)>>>;
+
+fn app(path: &std::path::Path, seen: Seen) -> CliApp<TestContext, Config> {
+    let root_handler = ParentHandler::<TestContext>::new().subcommand(
+        "install",
+        from_fn(move |ctx: TestContext, params: InstallParams| {
+            *seen.lock().unwrap() = Some((ctx.host, params.version));
+            Ok::<_, RpcError>(())
+        })
+        .no_display(),
+    );
+    CliApp::new(
+        |config: Config| Ok(TestContext { host: config.host }),
+        root_handler,
+    )
+    .with_config_file(path.with_extension("missing"))
+    .with_config_file(path)
+    .with_env_prefix("RPC_TOOLKIT_CONFIG_TEST")
+}
+
+#[test]
+fn test_config_layers() {
+    let path = std::env::temp_dir().join(format!("rpc-toolkit-config-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        "host = \"file-host\"\n\n[install]\nversion = \"1.0.0\"\n",
+    )
+    .unwrap();
+    let seen = Seen::default();
+
+    app(&path, seen.clone())
+        .run(["app", "install"].map(Into::into))
+        .unwrap();
+    assert_eq!(
+        seen.lock().unwrap().take(),
+        Some(("file-host".to_owned(), "1.0.0".to_owned()))
+    );
+
+    std::env::set_var("RPC_TOOLKIT_CONFIG_TEST_HOST", "env-host");
+    app(&path, seen.clone())
+        .run(["app", "install", "--version", "2.0.0"].map(Into::into))
+        .unwrap();
+    assert_eq!(
+        seen.lock().unwrap().take(),
+        Some(("env-host".to_owned(), "2.0.0".to_owned()))
+    );
+
+    app(&path, seen.clone())
+        .run(["app", "--host", "flag-host", "install"].map(Into::into))
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        seen.lock().unwrap().take(),
+        Some(("flag-host".to_owned(), "1.0.0".to_owned()))
+    );
+}
+
+#[test]
+fn test_unsupported_config_format() {
+    let path = std::env::temp_dir().join(format!("rpc-toolkit-config-{}.conf", std::process::id()));
+    std::fs::write(&path, "host = \"file-host\"\n").unwrap();
+    let res = app(&path, Seen::default()).run(["app", "install"].map(Into::into));
+    std::fs::remove_file(&path).unwrap();
+    let err = res.unwrap_err();
+    assert!(
+        err.data
+            .as_ref()
+            .and_then(|d| d.as_str())
+            .is_some_and(|d| d.contains("unsupported config format")),
+        "{:?}",
+        err
+    );
+}
@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use imbl_value::Value;
+use rpc_toolkit::{from_fn, CliApp, Context, Empty, HandlerExt, ParentHandler, Server};
+use serde_json::json;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn root_handler() -> ParentHandler<TestContext> {
+    ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>(
+            "list",
+            from_fn(|| Ok::<_, RpcError>(CALLS.fetch_add(1, Ordering::SeqCst))),
+        )
+        .alias("ls", "list")
+        .subcommand(
+            "old-status",
+            from_fn(|_: TestContext| Ok::<_, RpcError>("up".to_owned())).deprecated("use `status`"),
+        )
+}
+
+#[tokio::test]
+async fn test_deprecation() {
+    let server = Server::new(|| async { Ok(TestContext) }, root_handler());
+    let call = |method: &str| {
+        let req = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": {} });
+        server.handle(Ok(req.into()))
+    };
+
+    let res = call("list").await.unwrap();
+    assert!(res.get("result").is_some());
+    assert!(res.get("data").is_none());
+
+    let res = call("ls").await.unwrap();
+    assert!(res.get("result").is_some());
+    assert_eq!(res["data"]["deprecated"], Value::from("renamed to `list`"));
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+    let res = call("old-status").await.unwrap();
+    assert_eq!(res["result"], Value::from("up"));
+    assert_eq!(res["data"]["deprecated"], Value::from("use `status`"));
+
+    let cmd = CliApp::<TestContext, Empty>::new(|_| Ok(TestContext), root_handler()).into_command();
+    let visible: Vec<_> = cmd
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set())
+        .map(|c| c.get_name())
+        .collect();
+    assert!(visible.contains(&"list"));
+    assert!(!visible.contains(&"ls"));
+    assert!(!visible.contains(&"old-status"));
+
+    #[cfg(feature = "ts-rs")]
+    {
+        use rpc_toolkit::{HandlerTS, UnknownTS};
+        let typed = |name: &'static str| {
+            HandlerExt::<TestContext>::custom_ts(
+                from_fn(move || Ok::<_, RpcError>(name.to_owned())),
+                "{}".into(),
+                "string".into(),
+            )
+        };
+        let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+            .subcommand::<TestContext, _>("status", typed("status"))
+            .subcommand::<TestContext, _>(
+                "old-status",
+                HandlerExt::<TestContext>::deprecated(typed("up"), "use `status`"),
+            );
+        let ty = UnknownTS(root_handler).type_info().unwrap();
+        assert!(ty.contains("\"status\""), "{}", ty);
+        assert!(!ty.contains("\"old-status\""), "{}", ty);
+    }
+
+    CliApp::<TestContext, Empty>::new(|_| Ok(TestContext), root_handler())
+        .run_async(["app", "ls"].map(Into::into))
+        .await
+        .unwrap();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+}
@@ -0,0 +1,136 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use imbl_value::Value;
+use rpc_toolkit::{
+    from_fn, from_fn_async, Context, DynamicParentHandler, Empty, HandlerFor, ParentHandler, Server,
+};
+use tokio::sync::Notify;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+fn reply(s: &'static str) -> impl Fn() -> Result<String, RpcError> + Clone {
+    move || Ok(s.to_owned())
+}
+
+#[tokio::test]
+async fn test_dynamic_parent_handler() {
+    let plugins = DynamicParentHandler::<TestContext>::new();
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>("plugins", plugins.clone());
+    let server = Server::new(|| async { Ok(TestContext) }, root_handler);
+    let call = |method: &'static str| server.handle_command(method, imbl_value::json!({}));
+
+    let err = call("plugins.backup").await.unwrap_err();
+    assert_eq!(err.code, yajrc::METHOD_NOT_FOUND_ERROR.code);
+
+    #[cfg(feature = "ts-rs")]
+    {
+        use rpc_toolkit::{HandlerExt, HandlerTS, UnknownTS};
+        plugins.insert::<TestContext, _>(
+            "typed",
+            HandlerExt::<TestContext>::custom_ts(
+                from_fn(reply("typed")),
+                "{}".into(),
+                "string".into(),
+            ),
+        );
+        let ty = UnknownTS(plugins.clone()).type_info().unwrap();
+        assert!(ty.contains("\"typed\""), "{}", ty);
+        plugins.remove("typed");
+    }
+
+    plugins.insert::<TestContext, _>(
+        "backup",
+        from_fn(reply("backed up")).with_metadata("plugin", Value::from("backup")),
+    );
+    assert_eq!(
+        call("plugins.backup").await.unwrap(),
+        Value::from("backed up")
+    );
+    let method = plugins.method_from_dots("backup").unwrap();
+    assert_eq!(
+        plugins.metadata(method).get("plugin"),
+        Some(&Value::from("backup"))
+    );
+
+    // a request in flight keeps running against the subcommands it started with
+    let (started, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+    plugins.insert::<TestContext, _>("sync", {
+        let (started, release) = (started.clone(), release.clone());
+        from_fn_async(move || {
+            let (started, release) = (started.clone(), release.clone());
+            async move {
+                started.notify_one();
+                release.notified().await;
+                Ok::<_, RpcError>("old".to_owned())
+            }
+        })
+    });
+    let in_flight = tokio::spawn(call("plugins.sync"));
+    started.notified().await;
+    let prev = plugins
+        .replace(ParentHandler::new().subcommand::<TestContext, _>("sync", from_fn(reply("new"))));
+    assert!(prev.method_from_dots("backup").is_some());
+    release.notify_one();
+    assert_eq!(in_flight.await.unwrap().unwrap(), Value::from("old"));
+    assert_eq!(call("plugins.sync").await.unwrap(), Value::from("new"));
+    assert!(plugins.method_from_dots("backup").is_none());
+
+    assert!(plugins.remove("sync"));
+    assert!(!plugins.remove("sync"));
+    let err = call("plugins.sync").await.unwrap_err();
+    assert_eq!(err.code, yajrc::METHOD_NOT_FOUND_ERROR.code);
+}
+
+#[tokio::test]
+async fn test_dynamic_parent_handler_snapshot() {
+    let plugins = DynamicParentHandler::<TestContext>::new();
+    plugins.insert::<TestContext, _>("sync", from_fn(reply("old")));
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>("plugins", plugins.clone());
+    // hold the first request between resolving its method and dispatching it
+    let (started, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+    let gate = Arc::new(AtomicBool::new(true));
+    let server = Server::new(
+        {
+            let (started, release, gate) = (started.clone(), release.clone(), gate.clone());
+            move || {
+                let (started, release, gate) = (started.clone(), release.clone(), gate.clone());
+                async move {
+                    if gate.swap(false, Ordering::SeqCst) {
+                        started.notify_one();
+                        release.notified().await;
+                    }
+                    Ok(TestContext)
+                }
+            }
+        },
+        root_handler,
+    );
+
+    let in_flight = tokio::spawn(server.handle_command("plugins.sync", imbl_value::json!({})));
+    started.notified().await;
+    plugins.replace(ParentHandler::new());
+    release.notify_one();
+    assert_eq!(in_flight.await.unwrap().unwrap(), Value::from("old"));
+
+    // a panicking update changes nothing, and leaves the handler usable
+    let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        plugins.update(|_| panic!("update failed"))
+    }));
+    assert!(res.is_err());
+    plugins.insert::<TestContext, _>("sync", from_fn(reply("new")));
+    assert_eq!(
+        server
+            .handle_command("plugins.sync", imbl_value::json!({}))
+            .await
+            .unwrap(),
+        Value::from("new")
+    );
+}
@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use rpc_toolkit::reqwest::header::{HeaderName, HeaderValue};
+use rpc_toolkit::{
+    CallRemote, Context, Empty, HttpRemote, HttpRemoteContext, RetryPolicy, IDEMPOTENCY_KEY,
+};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use url::Url;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext(HttpRemote);
+
+impl Context for TestContext {}
+
+impl HttpRemoteContext for TestContext {
+    fn http_remote(&self) -> &HttpRemote {
+        &self.0
+    }
+}
+
+#[derive(Clone)]
+struct RemoteContext;
+
+impl Context for RemoteContext {}
+
+struct Request {
+    path: String,
+    headers: BTreeMap<String, String>,
+}
+
+fn decode_request(content_type: &str, body: &[u8]) -> serde_json::Value {
+    #[cfg(feature = "cbor")]
+    if content_type == "application/cbor" {
+        return serde_cbor::from_slice(body).unwrap();
+    }
+    assert_eq!(content_type, "application/json");
+    serde_json::from_slice(body).unwrap()
+}
+
+type Requests = tokio::sync::mpsc::UnboundedReceiver<Request>;
+
+/// Answers every request with `"ok"`, setting a session cookie, and reports what it received.
+/// The requests counted by the returned number are rejected as unavailable instead.
+async fn fake_server() -> (Url, Requests, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    let (send, recv) = tokio::sync::mpsc::unbounded_channel();
+    let unavailable = Arc::new(AtomicUsize::new(0));
+    let remaining = unavailable.clone();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            let mut conn = BufReader::new(conn);
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            let path = line.split(' ').nth(1).unwrap().to_owned();
+            let mut headers = BTreeMap::new();
+            loop {
+                line.clear();
+                conn.read_line(&mut line).await.unwrap();
+                let Some((name, value)) = line.trim_end().split_once(": ") else {
+                    break;
+                };
+                headers.insert(name.to_lowercase(), value.to_owned());
+            }
+            let mut body = vec![0; headers["content-length"].parse().unwrap()];
+            conn.read_exact(&mut body).await.unwrap();
+            let id = decode_request(&headers["content-type"], &body)["id"].clone();
+            send.send(Request { path, headers }).unwrap();
+
+            let rejected = remaining
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if rejected {
+                conn.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+                continue;
+            }
+            let body =
+                serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": "ok" }).to_string();
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nSet-Cookie: session=abc; Path=/\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            conn.write_all(res.as_bytes()).await.unwrap();
+        }
+    });
+    (url, recv, unavailable)
+}
+
+async fn try_call(
+    ctx: &TestContext,
+    metadata: OrdMap<&'static str, Value>,
+) -> Result<Value, RpcError> {
+    CallRemote::<RemoteContext>::call_remote(ctx, "echo", metadata, Value::Null, Empty {}).await
+}
+
+async fn call(ctx: &TestContext, metadata: OrdMap<&'static str, Value>) -> Value {
+    try_call(ctx, metadata).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_http_remote() {
+    let (url, mut requests, _) = fake_server().await;
+    let jar = std::env::temp_dir().join(format!("rpc-toolkit-cookies-{}.json", std::process::id()));
+    std::fs::remove_file(&jar).ok();
+    let remote = |url: Url| {
+        HttpRemote::new(url)
+            .with_path("rpc/v1")
+            .with_header(
+                HeaderName::from_static("x-app"),
+                HeaderValue::from_static("test"),
+            )
+            .with_metadata_header("scope", HeaderName::from_static("x-scope"))
+            .with_cookie_jar(&jar)
+    };
+    let ctx = TestContext(remote(url.clone()));
+
+    assert_eq!(call(&ctx, OrdMap::new()).await, Value::from("ok"));
+    let req = requests.recv().await.unwrap();
+    assert_eq!(req.path, "/rpc/v1");
+    assert_eq!(req.headers["x-app"], "test");
+    assert!(!req.headers.contains_key("cookie"));
+    assert!(!req.headers.contains_key("x-scope"));
+
+    let mut metadata = OrdMap::new();
+    metadata.insert("scope", Value::from("admin"));
+    call(&ctx, metadata).await;
+    let req = requests.recv().await.unwrap();
+    assert_eq!(req.headers["cookie"], "session=abc");
+    assert_eq!(req.headers["x-scope"], "admin");
+
+    // a fresh client picks the session back up from the jar
+    let ctx = TestContext(remote(url));
+    call(&ctx, OrdMap::new()).await;
+    let req = requests.recv().await.unwrap();
+    assert_eq!(req.headers["cookie"], "session=abc");
+
+    std::fs::remove_file(&jar).ok();
+}
+
+#[tokio::test]
+async fn test_retry() {
+    let (url, mut requests, unavailable) = fake_server().await;
+    let remote = HttpRemote::new(url).with_retry(RetryPolicy {
+        initial_backoff: Duration::from_millis(1),
+        ..Default::default()
+    });
+    let ctx = TestContext(remote.clone());
+    let mut idempotent = OrdMap::new();
+    idempotent.insert("idempotent", Value::from(true));
+
+    unavailable.store(2, Ordering::SeqCst);
+    assert_eq!(call(&ctx, idempotent).await, Value::from("ok"));
+    for _ in 0..3 {
+        let req = requests.recv().await.unwrap();
+        assert!(!req.headers.contains_key(IDEMPOTENCY_KEY));
+    }
+
+    unavailable.store(1, Ordering::SeqCst);
+    assert!(try_call(&ctx, OrdMap::new()).await.is_err());
+    assert!(!requests.recv().await.unwrap().headers[IDEMPOTENCY_KEY].is_empty());
+
+    let ctx = TestContext(remote.with_retry(RetryPolicy {
+        initial_backoff: Duration::from_millis(1),
+        retry_keyed: true,
+        ..Default::default()
+    }));
+    unavailable.store(1, Ordering::SeqCst);
+    assert_eq!(call(&ctx, OrdMap::new()).await, Value::from("ok"));
+    let first = requests.recv().await.unwrap().headers[IDEMPOTENCY_KEY].clone();
+    assert_eq!(
+        requests.recv().await.unwrap().headers[IDEMPOTENCY_KEY],
+        first
+    );
+}
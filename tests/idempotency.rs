@@ -0,0 +1,151 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Request};
+use http_body_util::BodyExt;
+use rpc_toolkit::{
+    from_fn, from_fn_async, Context, Empty, HttpServer, IdempotencyCache, ParentHandler, Server,
+    IDEMPOTENCY_KEY, IDEMPOTENCY_KEY_REUSED_ERROR, REQUEST_IN_PROGRESS_ERROR,
+};
+use serde_json::json;
+use tokio::sync::Notify;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+static CHARGES: AtomicU64 = AtomicU64::new(0);
+static READS: AtomicU64 = AtomicU64::new(0);
+
+fn request(key: &str, peer: [u8; 4], body: serde_json::Value) -> Request {
+    let mut req = Request::post("/rpc")
+        .header("content-type", "application/json")
+        .header(IDEMPOTENCY_KEY, key)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    req.extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from((peer, 4000))));
+    req
+}
+
+async fn send(server: &HttpServer<TestContext>, req: Request) -> serde_json::Value {
+    let res = server.handle(req).await;
+    serde_json::from_slice(&res.into_body().collect().await.unwrap().to_bytes()).unwrap()
+}
+
+fn rpc(method: &str, params: serde_json::Value) -> serde_json::Value {
+    json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params })
+}
+
+async fn call(server: &HttpServer<TestContext>, method: &str, key: &str) -> serde_json::Value {
+    send(server, request(key, [127, 0, 0, 1], rpc(method, json!({})))).await
+}
+
+#[tokio::test]
+async fn test_idempotency_cache() {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>(
+            "charge",
+            from_fn(|| Ok::<_, RpcError>(CHARGES.fetch_add(1, Ordering::SeqCst) + 1)),
+        )
+        .subcommand::<TestContext, _>(
+            "balance",
+            from_fn(|| Ok::<_, RpcError>(READS.fetch_add(1, Ordering::SeqCst) + 1))
+                .with_metadata("idempotent", true.into()),
+        );
+    let server = Server::new(|| async { Ok(TestContext) }, root_handler)
+        .middleware(IdempotencyCache::new(Duration::from_secs(60)));
+
+    assert_eq!(call(&server, "charge", "a").await["result"], 1);
+    assert_eq!(call(&server, "charge", "a").await["result"], 1);
+    assert_eq!(call(&server, "charge", "b").await["result"], 2);
+    assert_eq!(CHARGES.load(Ordering::SeqCst), 2);
+
+    assert_eq!(call(&server, "balance", "c").await["result"], 1);
+    assert_eq!(call(&server, "balance", "c").await["result"], 2);
+
+    // the same key with other params is a mistake, not a retry
+    let res = send(
+        &server,
+        request("a", [127, 0, 0, 1], rpc("charge", json!({ "amount": 5 }))),
+    )
+    .await;
+    assert_eq!(res["error"]["code"], IDEMPOTENCY_KEY_REUSED_ERROR.code);
+
+    // keys are scoped to the caller
+    let res = send(
+        &server,
+        request("a", [10, 0, 0, 2], rpc("charge", json!({}))),
+    )
+    .await;
+    assert_eq!(res["result"], 3);
+
+    // calls in a batch share the key, but not their results
+    let batch = || {
+        request(
+            "d",
+            [127, 0, 0, 1],
+            json!([
+                { "jsonrpc": "2.0", "id": 1, "method": "charge", "params": {} },
+                { "jsonrpc": "2.0", "id": 2, "method": "charge", "params": {} },
+            ]),
+        )
+    };
+    let results = |res: serde_json::Value| {
+        res.as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["result"].clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(results(send(&server, batch()).await), [json!(4), json!(5)]);
+    assert_eq!(results(send(&server, batch()).await), [json!(4), json!(5)]);
+    assert_eq!(CHARGES.load(Ordering::SeqCst), 5);
+}
+
+#[tokio::test]
+async fn test_idempotency_cancelled() {
+    let (started, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>(
+            "slow",
+            from_fn_async({
+                let (started, release) = (started.clone(), release.clone());
+                move || {
+                    let (started, release) = (started.clone(), release.clone());
+                    async move {
+                        started.notify_one();
+                        release.notified().await;
+                        Ok::<_, RpcError>("done".to_owned())
+                    }
+                }
+            }),
+        );
+    let server = Server::new(|| async { Ok(TestContext) }, root_handler)
+        .middleware(IdempotencyCache::new(Duration::from_secs(60)));
+
+    let in_flight = tokio::spawn({
+        let server = server.clone();
+        async move { call(&server, "slow", "e").await }
+    });
+    started.notified().await;
+    assert_eq!(
+        call(&server, "slow", "e").await["error"]["code"],
+        REQUEST_IN_PROGRESS_ERROR.code
+    );
+    // a client that goes away leaves nothing running behind the key
+    in_flight.abort();
+    let _ = in_flight.await;
+    let retry = tokio::spawn({
+        let server = server.clone();
+        async move { call(&server, "slow", "e").await }
+    });
+    started.notified().await;
+    release.notify_one();
+    assert_eq!(retry.await.unwrap()["result"], "done");
+}
@@ -0,0 +1,44 @@
+use rpc_toolkit::{from_fn, Context, Empty, Metrics, ParentHandler, Server};
+use serde_json::json;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+#[tokio::test]
+async fn test_metrics() {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>("echo", from_fn(|| Ok::<_, RpcError>("ok".to_owned())))
+        .subcommand::<TestContext, _>(
+            "say\"hi\"\tnow",
+            from_fn(|| Ok::<_, RpcError>("hi".to_owned())),
+        );
+    let metrics = Metrics::new();
+    let server =
+        Server::new(|| async { Ok(TestContext) }, root_handler).with_metrics(metrics.clone());
+
+    server
+        .handle(Ok(imbl_value::to_value(&json!([
+            { "jsonrpc": "2.0", "id": 1, "method": "echo", "params": {} },
+            { "jsonrpc": "2.0", "id": 2, "method": "missing", "params": {} },
+            { "jsonrpc": "2.0", "id": 3, "method": "say\"hi\"\tnow", "params": {} },
+        ]))
+        .unwrap()))
+        .await
+        .unwrap();
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("rpc_requests_total{method=\"echo\",code=\"ok\"} 1"));
+    assert!(rendered.contains("rpc_requests_total{method=\"unknown\",code=\"-32601\"} 1"));
+    assert!(rendered.contains("rpc_request_duration_seconds_count{method=\"echo\"} 1"));
+    // only quotes, backslashes and line feeds are escaped
+    assert!(
+        rendered.contains("rpc_requests_total{method=\"say\\\"hi\\\"\tnow\",code=\"ok\"} 1"),
+        "{}",
+        rendered
+    );
+    assert!(rendered.contains("rpc_requests_in_flight{transport=\"other\"} 0"));
+    assert!(rendered.contains("rpc_batch_size_count 1"));
+}
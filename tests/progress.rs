@@ -0,0 +1,141 @@
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
+
+use futures::StreamExt;
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use rpc_toolkit::{
+    call_remote_socket, from_fn_async, CallRemote, CliApp, Context, Empty, HandlerArgs,
+    ParentHandler, ProgressEvent, ProgressReporter, Server, PROGRESS_METHOD,
+};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+#[tokio::test]
+async fn test_progress_notifications() {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>(
+            "install",
+            from_fn_async(|args: HandlerArgs<TestContext>| async move {
+                let progress = args.progress().expect("transport supports progress");
+                for done in 1..=2 {
+                    progress.report(ProgressEvent::new(done, Some(2)).with_message("downloading"));
+                }
+                Ok::<_, RpcError>("installed".to_owned())
+            }),
+        );
+    let server = Server::new(|| async { Ok(TestContext) }, root_handler);
+
+    let requests = futures::stream::iter([Ok(imbl_value::to_value(&json!({
+        "jsonrpc": "2.0", "id": 7, "method": "install", "params": {}
+    }))
+    .unwrap())]);
+    let messages = server
+        .stream(requests)
+        .map(|res| serde_json::to_value(res.unwrap()).unwrap())
+        .collect::<Vec<_>>()
+        .await;
+
+    assert_eq!(messages.len(), 3);
+    for (done, message) in (1..=2).zip(&messages) {
+        assert_eq!(message["method"], PROGRESS_METHOD);
+        assert_eq!(
+            message["params"],
+            json!({ "id": 7, "done": done, "total": 2, "message": "downloading" })
+        );
+    }
+    assert_eq!(messages[2]["id"], 7);
+    assert_eq!(messages[2]["result"], "installed");
+}
+
+#[tokio::test]
+async fn test_call_remote_socket_progress() {
+    let (client, server) = tokio::io::duplex(1024);
+    let fake_server = tokio::spawn(async move {
+        let (r, mut w) = tokio::io::split(server);
+        let mut line = String::new();
+        BufReader::new(r).read_line(&mut line).await.unwrap();
+        let req: serde_json::Value = serde_json::from_str(&line).unwrap();
+        for message in [
+            json!({
+                "jsonrpc": "2.0",
+                "method": PROGRESS_METHOD,
+                "params": { "id": req["id"], "done": 3, "total": 4 },
+            }),
+            // the progress of another request on the same connection
+            json!({
+                "jsonrpc": "2.0",
+                "method": PROGRESS_METHOD,
+                "params": { "id": "other", "done": 1, "total": 2 },
+            }),
+            json!({ "jsonrpc": "2.0", "id": req["id"], "result": "done" }),
+        ] {
+            w.write_all(format!("{message}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+    });
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let reporter = {
+        let events = events.clone();
+        ProgressReporter::new(move |event| events.lock().unwrap().push(event))
+    };
+    let res = reporter
+        .scope(call_remote_socket(client, "install", json!({}).into()))
+        .await
+        .unwrap();
+    fake_server.await.unwrap();
+
+    assert_eq!(res, imbl_value::Value::from("done"));
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![ProgressEvent::new(3, Some(4))]
+    );
+}
+
+#[derive(Clone)]
+struct CliContext(Arc<Mutex<Vec<bool>>>);
+
+impl Context for CliContext {}
+
+impl CallRemote<TestContext> for CliContext {
+    async fn call_remote(
+        &self,
+        _: &str,
+        _: OrdMap<&'static str, Value>,
+        _: Value,
+        _: Empty,
+    ) -> Result<Value, RpcError> {
+        let progress = ProgressReporter::current();
+        if let Some(progress) = &progress {
+            progress.report(ProgressEvent::new(1, Some(2)));
+        }
+        self.0.lock().unwrap().push(progress.is_some());
+        Ok(Value::Null)
+    }
+}
+
+#[tokio::test]
+async fn test_cli_progress_bar() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    for format in ["human", "json"] {
+        let ctx = CliContext(seen.clone());
+        CliApp::<CliContext, Empty>::new(move |_| Ok(ctx), ParentHandler::new())
+            .with_rpc_command::<TestContext>()
+            .run_async(["app", "rpc", "install", "--format", format].map(Into::into))
+            .await
+            .unwrap();
+    }
+    // a bar is only drawn on a terminal, while other formats always hide progress
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [std::io::stderr().is_terminal(), true]
+    );
+}
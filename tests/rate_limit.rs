@@ -0,0 +1,65 @@
+use axum::body::Body;
+use axum::extract::Request;
+use http_body_util::BodyExt;
+use rpc_toolkit::{from_fn, Context, Empty, ParentHandler, RateLimiter, Server};
+use serde_json::json;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+async fn call(server: &rpc_toolkit::HttpServer<TestContext>, id: u64) -> serde_json::Value {
+    let res = server
+        .handle(
+            Request::post("/rpc")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    json!({ "jsonrpc": "2.0", "id": id, "method": "login", "params": {} })
+                        .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await;
+    serde_json::from_slice(&res.into_body().collect().await.unwrap().to_bytes()).unwrap()
+}
+
+#[tokio::test]
+async fn test_rate_limit() {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>(
+            "login",
+            from_fn(|| Ok::<_, RpcError>("ok".to_owned()))
+                .with_metadata("rate_limit", json!({ "per_sec": 0.001, "burst": 1 }).into()),
+        );
+    let server =
+        Server::new(|| async { Ok(TestContext) }, root_handler).middleware(RateLimiter::new());
+
+    assert_eq!(call(&server, 1).await["result"], "ok");
+    let limited = call(&server, 2).await;
+    assert_eq!(limited["id"], 2);
+    assert_eq!(limited["error"]["code"], -32029);
+    assert!(limited["error"]["data"]["retry_after"].as_f64().unwrap() > 0.0);
+}
+
+#[tokio::test]
+async fn test_invalid_rate_limit() {
+    for rate_limit in [
+        json!({ "per_sec": 0.0, "burst": 1 }),
+        json!({ "per_sec": -1.0, "burst": 1 }),
+        json!({ "per_sec": 1.0, "burst": 0 }),
+    ] {
+        let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+            .subcommand::<TestContext, _>(
+                "login",
+                from_fn(|| Ok::<_, RpcError>("ok".to_owned()))
+                    .with_metadata("rate_limit", rate_limit.into()),
+            );
+        let server =
+            Server::new(|| async { Ok(TestContext) }, root_handler).middleware(RateLimiter::new());
+
+        let res = call(&server, 1).await;
+        assert_eq!(res["error"]["code"], yajrc::INTERNAL_ERROR.code, "{}", res);
+    }
+}
@@ -0,0 +1,68 @@
+use clap::Parser;
+use rpc_toolkit::util::{redact_error, Secret};
+use rpc_toolkit::{from_fn, Context, Empty, HandlerExt, ParentHandler, Server};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+#[derive(Debug, Deserialize, Serialize, Parser)]
+struct LoginParams {
+    user: String,
+    pin: Secret<u32>,
+}
+
+#[tokio::test]
+async fn test_redact_errors() {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new().subcommand(
+        "login",
+        from_fn(|_: TestContext, params: LoginParams| Ok::<_, RpcError>(format!("{params:?}")))
+            .with_sensitive(&["pin"])
+            .no_ts(),
+    );
+    let server = Server::new(|| async { Ok(TestContext) }, root_handler);
+
+    let res = server
+        .handle_command(
+            "login",
+            imbl_value::to_value(&json!({ "user": "bob", "pin": 1234 })).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        res,
+        imbl_value::Value::from("LoginParams { user: \"bob\", pin: *** }")
+    );
+
+    let err = server
+        .handle_command(
+            "login",
+            imbl_value::to_value(&json!({ "user": "bob", "pin": "hunter2" })).unwrap(),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, yajrc::INVALID_PARAMS_ERROR.code);
+    let data = err.data.unwrap().to_string();
+    assert!(!data.contains("hunter2"), "{}", data);
+    assert!(data.contains("***"), "{}", data);
+}
+
+#[test]
+fn test_redact_error_bounds() {
+    let params = imbl_value::json!({ "user": "bob", "pin": 1, "token": "abcd1234" });
+    let err = RpcError {
+        message: "bad token abcd1234 on line 1, not abcd12345".into(),
+        data: Some(json!({ "pin": 1, "token": "abcd1234", "detail": "(abcd1234)" })),
+        ..yajrc::INTERNAL_ERROR
+    };
+    let err = redact_error(err, &params, &["pin", "token"]);
+    assert_eq!(err.message, "bad token *** on line 1, not abcd12345");
+    assert_eq!(
+        err.data.unwrap(),
+        json!({ "pin": "***", "token": "***", "detail": "(***)" })
+    );
+}
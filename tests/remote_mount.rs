@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+
+use axum::handler::Handler;
+use clap::Parser;
+use imbl_value::Value;
+use rpc_toolkit::{
+    from_fn, remote_mount, ClientError, ClientMiddleware, ClientRequest, Context, Empty,
+    HttpRemote, ParentHandler, RemoteMount, Server,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::TcpListener;
+use url::Url;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+#[derive(Debug, Deserialize, Serialize, Parser)]
+#[cfg_attr(feature = "ts-rs", derive(ts_rs::TS))]
+struct UsageParams {
+    disk: String,
+}
+
+async fn serve_backend() -> Url {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>("status", from_fn(|| Ok::<_, RpcError>("up".to_owned())))
+        .subcommand::<TestContext, _>(
+            "disk",
+            ParentHandler::<TestContext, Empty, Empty>::new().subcommand::<TestContext, _>(
+                "usage",
+                from_fn(|_: TestContext, params: UsageParams| {
+                    Ok::<_, RpcError>(format!("{} is 42% full", params.disk))
+                }),
+            ),
+        );
+    let server = Server::new(|| async { Ok(TestContext) }, root_handler).for_http();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, server.with_state(()).into_make_service())
+            .await
+            .unwrap()
+    });
+    url
+}
+
+#[tokio::test]
+async fn test_remote_mount() {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>("node2", remote_mount(serve_backend().await));
+    let gateway = Server::new(|| async { Ok(TestContext) }, root_handler);
+
+    assert_eq!(
+        gateway
+            .handle_command("node2.status", json!({}).into())
+            .await
+            .unwrap(),
+        Value::from("up")
+    );
+    assert_eq!(
+        gateway
+            .handle_command("node2.disk.usage", json!({ "disk": "sda" }).into())
+            .await
+            .unwrap(),
+        Value::from("sda is 42% full")
+    );
+
+    let err = gateway
+        .handle_command("node2.missing", json!({}).into())
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, yajrc::METHOD_NOT_FOUND_ERROR.code);
+    let err = gateway
+        .handle_command("node2", json!({}).into())
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, yajrc::METHOD_NOT_FOUND_ERROR.code);
+}
+
+/// The method and `idempotent` metadata of each forwarded call.
+type Sent = (String, Option<Value>);
+
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<Sent>>>);
+
+impl ClientMiddleware for Capture {
+    async fn process_request(&mut self, request: &mut ClientRequest) -> Result<(), ClientError> {
+        self.0.lock().unwrap().push((
+            request.method.clone(),
+            request.metadata.get("idempotent").cloned(),
+        ));
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_remote_mount_forwards_call() {
+    let capture = Capture::default();
+    let remote = HttpRemote::new(serve_backend().await).with_middleware(capture.clone());
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>(
+            "node2",
+            RemoteMount::new(remote).with_metadata("idempotent", Value::Bool(true)),
+        );
+    let gateway = Server::new(|| async { Ok(TestContext) }, root_handler);
+
+    let method = "node2.disk.usage";
+    gateway
+        .handle_command(method, json!({ "disk": "sdb" }).into())
+        .await
+        .unwrap();
+    let err = gateway
+        .handle_command("node2.disk..usage", json!({}).into())
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, yajrc::METHOD_NOT_FOUND_ERROR.code);
+    assert_eq!(
+        *capture.0.lock().unwrap(),
+        [("disk.usage".to_owned(), Some(Value::Bool(true)))]
+    );
+}
@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::handler::Handler;
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use rpc_toolkit::reqwest::header::{HeaderMap, CONTENT_TYPE};
+use rpc_toolkit::reqwest::Client;
+use rpc_toolkit::{
+    call_remote_socket_typed, from_fn, ClientError, ClientMiddleware, ClientRequest, Context,
+    Empty, HmacSigner, HmacVerifier, HttpRemote, ParentHandler, Server, INVALID_SIGNATURE_ERROR,
+};
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct TestContext;
+
+impl Context for TestContext {}
+
+/// Keeps the headers of the last request, as sent.
+#[derive(Clone)]
+struct Capture(Arc<Mutex<HeaderMap>>);
+impl ClientMiddleware for Capture {
+    async fn process_request(&mut self, request: &mut ClientRequest) -> Result<(), ClientError> {
+        *self.0.lock().unwrap() = request.headers.clone();
+        Ok(())
+    }
+}
+
+async fn call(remote: &HttpRemote) -> Result<Value, ClientError> {
+    remote
+        .call("ping", OrdMap::new(), imbl_value::json!({}))
+        .await
+}
+
+fn signature_error(res: Result<Value, ClientError>) -> serde_json::Value {
+    match res {
+        Err(ClientError::Rpc(e)) => {
+            assert_eq!(e.code, INVALID_SIGNATURE_ERROR.code);
+            e.data.unwrap()
+        }
+        res => panic!("expected a signature error, got {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn test_hmac_signing() {
+    let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+        .subcommand::<TestContext, _>("ping", from_fn(|| Ok::<_, RpcError>("pong".to_owned())));
+    let server = Server::new(|| async { Ok(TestContext) }, root_handler)
+        .middleware(HmacVerifier::new("secret", Duration::from_secs(30)));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url: Url = format!("http://{}/", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, server.with_state(()).into_make_service())
+            .await
+            .unwrap()
+    });
+
+    let captured = Arc::new(Mutex::new(HeaderMap::new()));
+    let signed = HttpRemote::new(url.clone())
+        .with_middleware(Capture(captured.clone()))
+        .with_middleware(HmacSigner::new("secret"));
+    assert_eq!(call(&signed).await.unwrap(), Value::from("pong"));
+    assert_eq!(call(&signed).await.unwrap(), Value::from("pong"));
+
+    let unsigned = HttpRemote::new(url.clone());
+    assert_eq!(signature_error(call(&unsigned).await), "missing signature");
+
+    let wrong_key = HttpRemote::new(url.clone()).with_middleware(HmacSigner::new("guess"));
+    assert_eq!(
+        signature_error(call(&wrong_key).await),
+        "signature mismatch"
+    );
+
+    let mut replayed = HttpRemote::new(url.clone());
+    for (name, value) in captured.lock().unwrap().iter() {
+        replayed = replayed.with_header(name.clone(), value.clone());
+    }
+    assert_eq!(signature_error(call(&replayed).await), "nonce already used");
+
+    // one signature cannot cover a batch
+    let call = json!({ "jsonrpc": "2.0", "id": 1, "method": "ping", "params": {} });
+    let headers = captured.lock().unwrap().clone();
+    let res = Client::new()
+        .post(url)
+        .headers(headers)
+        .header(CONTENT_TYPE, "application/json")
+        .body(json!([call, call]).to_string())
+        .send()
+        .await
+        .unwrap();
+    let res: serde_json::Value = serde_json::from_slice(&res.bytes().await.unwrap()).unwrap();
+    for res in res.as_array().unwrap() {
+        assert_eq!(res["error"]["code"], INVALID_SIGNATURE_ERROR.code);
+        assert_eq!(res["error"]["data"], "batches cannot be signed");
+    }
+}
+
+#[tokio::test]
+async fn test_hmac_signing_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let root_handler = ParentHandler::<TestContext, Empty, Empty>::new()
+            .subcommand::<TestContext, _>("ping", from_fn(|| Ok::<_, RpcError>("pong".to_owned())));
+        let server = Server::new(|| async { Ok(TestContext) }, root_handler)
+            .for_socket()
+            .middleware(HmacVerifier::new("secret", Duration::from_secs(30)));
+        let listener = tokio_stream::wrappers::TcpListenerStream::new(listener);
+        let (_, run) = server.run_socket(listener, |e| panic!("{}", e));
+        run.await
+    });
+    let res = call_remote_socket_typed(
+        TcpStream::connect(addr).await.unwrap(),
+        "ping",
+        imbl_value::json!({}),
+    )
+    .await;
+    assert_eq!(signature_error(res), "socket calls cannot be signed");
+}
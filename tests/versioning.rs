@@ -0,0 +1,198 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::handler::Handler;
+use http_body_util::BodyExt;
+use imbl_value::imbl::OrdMap;
+use imbl_value::Value;
+use rpc_toolkit::reqwest::header::CONTENT_TYPE;
+use rpc_toolkit::reqwest::Client;
+use rpc_toolkit::{
+    call_remote_http, call_remote_socket, from_fn, ApiVersion, CallRemote, CliApp, Context, Empty,
+    HmacVerifier, ParentHandler, Server, API_VERSION_HEADER, INVALID_SIGNATURE_ERROR,
+    VERSION_METHOD,
+};
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+use yajrc::RpcError;
+
+#[derive(Clone)]
+struct ServerContext;
+
+impl Context for ServerContext {}
+
+fn server() -> Server<ServerContext> {
+    let root_handler = ParentHandler::<ServerContext, Empty, Empty>::new()
+        .subcommand::<ServerContext, _>(
+            "stable",
+            from_fn(|| Ok::<_, RpcError>("stable".to_owned())),
+        )
+        .subcommand::<ServerContext, _>(
+            "old",
+            from_fn(|| Ok::<_, RpcError>("old".to_owned()))
+                .with_metadata("removed", Value::from("2")),
+        )
+        .subcommand::<ServerContext, _>(
+            "new",
+            from_fn(|| Ok::<_, RpcError>("new".to_owned()))
+                .with_metadata("introduced", Value::from("2.0")),
+        );
+    Server::new(|| async { Ok(ServerContext) }, root_handler).with_api_version(v("2.1"))
+}
+
+fn v(s: &str) -> ApiVersion {
+    s.parse().unwrap()
+}
+
+#[derive(Clone)]
+enum ClientContext {
+    Http(Url),
+    Socket(SocketAddr),
+}
+
+impl Context for ClientContext {}
+
+#[derive(Clone)]
+struct RemoteContext;
+
+impl Context for RemoteContext {}
+
+impl CallRemote<RemoteContext> for ClientContext {
+    async fn call_remote(
+        &self,
+        method: &str,
+        _: OrdMap<&'static str, Value>,
+        params: Value,
+        _: Empty,
+    ) -> Result<Value, RpcError> {
+        match self {
+            Self::Http(url) => call_remote_http(&Client::new(), url.clone(), method, params).await,
+            Self::Socket(addr) => {
+                call_remote_socket(TcpStream::connect(addr).await.unwrap(), method, params).await
+            }
+        }
+    }
+}
+
+async fn rpc(ctx: ClientContext, version: Option<&str>, method: &str) -> Result<(), RpcError> {
+    let mut app = CliApp::<ClientContext, Empty>::new(move |_| Ok(ctx), ParentHandler::new())
+        .with_rpc_command::<RemoteContext>();
+    if let Some(version) = version {
+        app = app.with_api_version(v(version));
+    }
+    app.run_async(["app", "rpc", method].map(Into::into)).await
+}
+
+fn assert_not_found(res: Result<(), RpcError>) {
+    let err = res.unwrap_err();
+    assert_eq!(err.code, yajrc::METHOD_NOT_FOUND_ERROR.code, "{}", err);
+}
+
+#[test]
+fn test_api_version() {
+    assert_eq!(v("2"), v("2.0.0"));
+    assert!(v("2.1") > v("2.0.9"));
+    assert!(v("10") > v("9.9"));
+    assert_eq!(v("1.2.3").to_string(), "1.2.3");
+    assert!("1..2".parse::<ApiVersion>().is_err());
+    assert!("".parse::<ApiVersion>().is_err());
+
+    assert!(v("2.0").is_compatible_with(&v("2.1")));
+    assert!(!v("2.2").is_compatible_with(&v("2.1")));
+    assert!(!v("1.0").is_compatible_with(&v("2.1")));
+}
+
+#[tokio::test]
+async fn test_http_versioning() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url: Url = format!("http://{}/", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    let server = server().for_http();
+    tokio::spawn(async move {
+        axum::serve(listener, server.with_state(()).into_make_service())
+            .await
+            .unwrap()
+    });
+    let ctx = ClientContext::Http(url.clone());
+
+    // without a declared version, clients see the server's own
+    rpc(ctx.clone(), None, "new").await.unwrap();
+    assert_not_found(rpc(ctx.clone(), None, "old").await);
+
+    rpc(ctx.clone(), Some("1.5"), "old").await.unwrap();
+    rpc(ctx.clone(), Some("1.5"), "stable").await.unwrap();
+    assert_not_found(rpc(ctx.clone(), Some("1.5"), "new").await);
+    rpc(ctx.clone(), Some("2.0"), "new").await.unwrap();
+
+    let res = Client::new()
+        .post(url)
+        .header(API_VERSION_HEADER, "1")
+        .header(CONTENT_TYPE, "application/json")
+        .body(json!({ "jsonrpc": "2.0", "id": 0, "method": "new", "params": {} }).to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()[API_VERSION_HEADER], "2.1");
+    let res: serde_json::Value = serde_json::from_slice(&res.bytes().await.unwrap()).unwrap();
+    assert_eq!(res["error"]["code"], yajrc::METHOD_NOT_FOUND_ERROR.code);
+    assert_eq!(
+        res["error"]["data"],
+        "new is not available in API version 1"
+    );
+}
+
+#[tokio::test]
+async fn test_handshake_goes_through_middleware() {
+    let server = server().middleware(HmacVerifier::new("secret", Duration::from_secs(30)));
+    let res = server
+        .handle(
+            Request::post("/rpc")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": 0,
+                        "method": VERSION_METHOD,
+                        "params": { "version": "2" },
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await;
+    let res: serde_json::Value =
+        serde_json::from_slice(&res.into_body().collect().await.unwrap().to_bytes()).unwrap();
+    assert_eq!(res["error"]["code"], INVALID_SIGNATURE_ERROR.code);
+}
+
+#[tokio::test]
+async fn test_socket_versioning() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let server = server().for_socket();
+        let listener = tokio_stream::wrappers::TcpListenerStream::new(listener);
+        let (_, run) = server.run_socket(listener, |e| panic!("{}", e));
+        run.await
+    });
+    let ctx = ClientContext::Socket(addr);
+
+    rpc(ctx.clone(), None, "new").await.unwrap();
+    assert_not_found(rpc(ctx.clone(), None, "old").await);
+
+    rpc(ctx.clone(), Some("1.5"), "old").await.unwrap();
+    assert_not_found(rpc(ctx.clone(), Some("1.5"), "new").await);
+
+    let res = call_remote_socket(
+        TcpStream::connect(addr).await.unwrap(),
+        rpc_toolkit::VERSION_METHOD,
+        imbl_value::json!({ "version": "1" }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(res, imbl_value::json!({ "version": "2.1" }));
+}